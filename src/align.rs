@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Map, Value};
+
+use crate::api::{article_link, normalize_title, page_languages};
+use crate::site::split_link;
+use crate::wikidata::{entities, entity_json};
+use crate::{
+    plaintext_from_raw, raw_articles_from_links, read_lines, DownloadOptions, TITLES_PER_REQUEST,
+};
+
+// Articles given as links stay on their wiki, bare titles are looked up on the first language's Wikipedia
+fn host_and_title(article: &str, langs: &[String]) -> Result<(String, String), String> {
+    if article.starts_with("http://") || article.starts_with("https://") {
+        let (host, url_title) = split_link(article)?;
+        let title = percent_decode_str(&url_title).decode_utf8_lossy();
+        Ok((host, normalize_title(&title)))
+    } else {
+        Ok((
+            format!("{}.wikipedia.org", langs[0]),
+            normalize_title(article),
+        ))
+    }
+}
+
+// Writes one JSON line per article with its versions in each of `langs`, keyed by the Wikidata item
+pub fn align(
+    articles: &[String],
+    links_file: Option<&Path>,
+    langs: &[String],
+    output: &Path,
+    options: &DownloadOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut articles = articles.to_vec();
+    if let Some(links_file) = links_file {
+        for line in read_lines(links_file)?.map_while(Result::ok) {
+            if !line.trim().is_empty() {
+                articles.push(line.trim().to_string());
+            }
+        }
+    }
+
+    let mut by_host: HashMap<String, Vec<String>> = HashMap::new();
+    for article in &articles {
+        match host_and_title(article, langs) {
+            Ok((host, title)) => by_host.entry(host).or_default().push(title),
+            Err(err) => println!("\x1b[31m⚠️ {}: {}\x1b[0m", article, err),
+        }
+    }
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut aligned_count = 0;
+    println!(
+        "⚡ Aligning {} articles across {}...",
+        articles.len(),
+        langs.join(", ")
+    );
+
+    for (host, titles) in by_host {
+        // The article itself stands for the language of its wiki, or the first one
+        let host_lang = host
+            .split('.')
+            .next()
+            .filter(|lang| langs.iter().any(|wanted| wanted == lang))
+            .unwrap_or(&langs[0])
+            .to_string();
+
+        for chunk in titles.chunks(TITLES_PER_REQUEST) {
+            let pages = match page_languages(&host, chunk) {
+                Ok(pages) => pages,
+                Err(err) => {
+                    println!("\x1b[31m⚠️ {}\x1b[0m", err);
+                    continue;
+                }
+            };
+
+            // Each language is fetched in one go, the links all point to the same wiki
+            let mut records: Vec<Map<String, Value>> = pages.iter().map(|_| Map::new()).collect();
+            for lang in langs {
+                let wanted: Vec<(usize, String)> = pages
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, page)| {
+                        let link = if *lang == host_lang {
+                            article_link(&host, &page.title)
+                        } else {
+                            page.links.get(lang)?.clone()
+                        };
+                        Some((index, link))
+                    })
+                    .collect();
+                let links: Vec<String> = wanted.iter().map(|(_, link)| link.clone()).collect();
+                let fetched = raw_articles_from_links(&links, options.as_of.as_deref());
+                for ((index, link), article) in wanted.into_iter().zip(fetched) {
+                    match article {
+                        Ok(article) => {
+                            records[index].insert(
+                                lang.clone(),
+                                json!({
+                                    "title": normalize_title(&article.title),
+                                    "revid": article.revid,
+                                    "text": plaintext_from_raw(&article),
+                                }),
+                            );
+                        }
+                        Err(err) => println!("\x1b[31m⚠️ {}: {}\x1b[0m", link, err),
+                    }
+                }
+            }
+
+            let entities = if options.wikidata {
+                let ids: Vec<String> = pages.iter().filter_map(|page| page.item.clone()).collect();
+                entities(&host, &ids).unwrap_or_else(|err| {
+                    println!("\x1b[31m⚠️ {}\x1b[0m", err);
+                    HashMap::new()
+                })
+            } else {
+                HashMap::new()
+            };
+
+            for (page, articles) in pages.iter().zip(records) {
+                let mut record = json!({
+                    "item": page.item,
+                    "articles": articles,
+                });
+                if options.wikidata {
+                    record["wikidata"] = json!(page
+                        .item
+                        .as_ref()
+                        .and_then(|item| entities.get(item))
+                        .map(entity_json));
+                }
+                writeln!(writer, "{}", record)?;
+                aligned_count += 1;
+            }
+        }
+    }
+
+    writer.flush()?;
+    println!(
+        "\x1B[32mSaved {} aligned articles to {}\x1B[0m",
+        aligned_count,
+        output.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::wiki_link;
+
+    #[test]
+    fn aligns_versions_by_wikidata_item() {
+        let output = std::env::temp_dir().join(format!("wp-align-{}.jsonl", std::process::id()));
+        align(
+            &[wiki_link("Kolkata")],
+            None,
+            &["en".to_string(), "hi".to_string(), "bn".to_string()],
+            &output,
+            &DownloadOptions {
+                wikidata: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let contents = std::fs::read_to_string(&output).unwrap();
+        std::fs::remove_file(&output).unwrap();
+
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["item"], "Q1348");
+        assert_eq!(lines[0]["articles"]["en"]["revid"], 1221000001);
+        assert_eq!(lines[0]["articles"]["hi"]["title"], "कोलकाता");
+        assert_eq!(
+            lines[0]["articles"]["hi"]["text"],
+            "कोलकाता पश्चिम बंगाल की राजधानी है।"
+        );
+        assert_eq!(lines[0]["wikidata"]["instance_of"][1]["label"], "city");
+        // There is no Bengali version in the fixture
+        assert!(lines[0]["articles"]["bn"].is_null());
+    }
+}
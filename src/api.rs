@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, HashMap};
+
+use percent_encoding::percent_decode_str;
+use serde_json::Value;
+use url::Url;
+
+use crate::http::get_json;
+use crate::site::profile;
+
+// Builds the link to an article the same way the wiki's own pages do
+pub fn article_link(host: &str, title: &str) -> String {
+    profile(host).article_url(title)
+}
+
+// "Indian_mathematicians", "indian mathematicians" and "Indian%20mathematicians"
+// all point to the same article
+pub fn normalize_title(title: &str) -> String {
+    let decoded = percent_decode_str(title)
+        .decode_utf8_lossy()
+        .replace('_', " ");
+    let collapsed = decoded.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut chars = collapsed.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+pub fn query(host: &str, params: &[(&str, &str)]) -> Result<Value, String> {
+    api_request(host, "query", params)
+}
+
+pub fn api_request(host: &str, action: &str, params: &[(&str, &str)]) -> Result<Value, String> {
+    let mut url = Url::parse(&profile(host).api_url())
+        .map_err(|err| format!("Error: Invalid API URL due to {}", err))?;
+    url.query_pairs_mut()
+        .append_pair("action", action)
+        .append_pair("format", "json")
+        .append_pair("formatversion", "2")
+        .extend_pairs(params);
+
+    let response = get_json(url.as_str())?;
+
+    if let Some(error) = response.get("error") {
+        return Err(format!("Error: API returned {}", error["info"]));
+    }
+    Ok(response)
+}
+
+pub fn random_titles(host: &str, count: usize) -> Result<Vec<String>, String> {
+    let mut titles = vec![];
+    // The API caps rnlimit at 500 for regular users
+    while titles.len() < count {
+        let limit = (count - titles.len()).min(500).to_string();
+        let response = query(
+            host,
+            &[
+                ("list", "random"),
+                ("rnnamespace", "0"),
+                ("rnlimit", &limit),
+            ],
+        )?;
+        let batch = response["query"]["random"]
+            .as_array()
+            .ok_or("Error: Unexpected response for random articles.")?;
+        if batch.is_empty() {
+            break;
+        }
+        titles.extend(
+            batch
+                .iter()
+                .filter_map(|page| page["title"].as_str())
+                .map(|title| title.to_string()),
+        );
+    }
+    Ok(titles)
+}
+
+pub struct PageLanguages {
+    pub title: String,
+    // Wikidata item, e.g. Q1348
+    pub item: Option<String>,
+    // Links to the same article on other wikis, by language code
+    pub links: BTreeMap<String, String>,
+}
+
+// Interlanguage links and Wikidata item of each of the titles, in the order they were asked for.
+// Titles are followed through redirects, missing pages are left out
+pub fn page_languages(host: &str, titles: &[String]) -> Result<Vec<PageLanguages>, String> {
+    let joined_titles = titles.join("|");
+    let mut pages: BTreeMap<String, PageLanguages> = BTreeMap::new();
+    let mut renamed: HashMap<String, String> = HashMap::new();
+    let mut continue_params: Vec<(String, String)> = vec![];
+    loop {
+        let mut params = vec![
+            ("prop", "langlinks|pageprops"),
+            ("titles", joined_titles.as_str()),
+            ("ppprop", "wikibase_item"),
+            ("llprop", "url"),
+            ("lllimit", "max"),
+            ("redirects", "1"),
+        ];
+        params.extend(
+            continue_params
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+        let response = query(host, &params)?;
+
+        note_renamed(&response, &mut renamed);
+        for page in response["query"]["pages"].as_array().into_iter().flatten() {
+            let Some(title) = page["title"].as_str() else {
+                continue;
+            };
+            if page["missing"].as_bool() == Some(true) {
+                continue;
+            }
+            let entry = pages
+                .entry(title.to_string())
+                .or_insert_with(|| PageLanguages {
+                    title: title.to_string(),
+                    item: None,
+                    links: BTreeMap::new(),
+                });
+            if let Some(item) = page["pageprops"]["wikibase_item"].as_str() {
+                entry.item = Some(item.to_string());
+            }
+            for link in page["langlinks"].as_array().into_iter().flatten() {
+                if let (Some(lang), Some(url)) = (link["lang"].as_str(), link["url"].as_str()) {
+                    entry.links.insert(lang.to_string(), url.to_string());
+                }
+            }
+        }
+
+        // Long lists of language links come in several parts
+        match response["continue"].as_object() {
+            Some(tokens) => {
+                continue_params = tokens
+                    .iter()
+                    .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                    .collect()
+            }
+            None => break,
+        }
+    }
+
+    Ok(titles
+        .iter()
+        .filter_map(|title| pages.remove(&resolve_title(&renamed, title)))
+        .collect())
+}
+
+// Wikidata items of the titles, keyed by the titles as they were asked for
+pub fn item_ids(host: &str, titles: &[String]) -> Result<HashMap<String, String>, String> {
+    let response = query(
+        host,
+        &[
+            ("prop", "pageprops"),
+            ("titles", &titles.join("|")),
+            ("ppprop", "wikibase_item"),
+            ("redirects", "1"),
+        ],
+    )?;
+    let mut renamed: HashMap<String, String> = HashMap::new();
+    note_renamed(&response, &mut renamed);
+    let items: HashMap<&str, &str> = response["query"]["pages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|page| {
+            Some((
+                page["title"].as_str()?,
+                page["pageprops"]["wikibase_item"].as_str()?,
+            ))
+        })
+        .collect();
+    Ok(titles
+        .iter()
+        .filter_map(|title| {
+            let item = items.get(resolve_title(&renamed, title).as_str())?;
+            Some((title.clone(), item.to_string()))
+        })
+        .collect())
+}
+
+pub struct PageInfo {
+    pub title: String,
+    // Size of the wikitext in bytes
+    pub length: u64,
+    pub disambiguation: bool,
+    pub item: Option<String>,
+}
+
+impl PageInfo {
+    // From a page of a prop=info|pageprops query
+    fn from_page(page: &Value) -> Option<Self> {
+        Some(PageInfo {
+            title: page["title"].as_str()?.to_string(),
+            length: page["length"].as_u64()?,
+            // The property is there, empty, on disambiguation pages
+            disambiguation: page["pageprops"].get("disambiguation").is_some(),
+            item: page["pageprops"]["wikibase_item"]
+                .as_str()
+                .map(str::to_string),
+        })
+    }
+}
+
+// Size, disambiguation flag and Wikidata item of the titles, keyed by the titles as they were asked for
+pub fn page_info(host: &str, titles: &[String]) -> Result<HashMap<String, PageInfo>, String> {
+    let response = query(
+        host,
+        &[
+            ("prop", "info|pageprops"),
+            ("titles", &titles.join("|")),
+            ("ppprop", "disambiguation|wikibase_item"),
+        ],
+    )?;
+    let mut renamed: HashMap<String, String> = HashMap::new();
+    note_renamed(&response, &mut renamed);
+    let mut pages: HashMap<String, PageInfo> = HashMap::new();
+    for page in response["query"]["pages"].as_array().into_iter().flatten() {
+        if let Some(info) = PageInfo::from_page(page) {
+            pages.insert(info.title.clone(), info);
+        }
+    }
+    Ok(titles
+        .iter()
+        .filter_map(|title| {
+            let info = pages.remove(&resolve_title(&renamed, title))?;
+            Some((title.clone(), info))
+        })
+        .collect())
+}
+
+// Titles the API normalized or followed a redirect from
+fn note_renamed(response: &Value, renamed: &mut HashMap<String, String>) {
+    for kind in ["normalized", "redirects"] {
+        for entry in response["query"][kind].as_array().into_iter().flatten() {
+            if let (Some(from), Some(to)) = (entry["from"].as_str(), entry["to"].as_str()) {
+                renamed.insert(from.to_string(), to.to_string());
+            }
+        }
+    }
+}
+
+fn resolve_title(renamed: &HashMap<String, String>, title: &str) -> String {
+    // Normalized first, then redirected
+    let mut title = title.to_string();
+    for _ in 0..2 {
+        if let Some(to) = renamed.get(&title) {
+            title = to.clone();
+        }
+    }
+    title
+}
+
+pub struct CategoryMembers {
+    pub pages: Vec<String>,
+    pub subcategories: Vec<String>,
+}
+
+// Pages of a namespace in title order from `from` on, redirects left out, handed over a response
+// at a time. The size bounds are applied by the wiki, same as on Special:AllPages
+pub fn all_pages(
+    host: &str,
+    namespace: i64,
+    from: Option<&str>,
+    (min_size, max_size): (Option<u64>, Option<u64>),
+    mut on_batch: impl FnMut(Vec<PageInfo>),
+) -> Result<usize, String> {
+    let namespace = namespace.to_string();
+    let min_size = min_size.map(|size| size.to_string());
+    let max_size = max_size.map(|size| size.to_string());
+    let mut batch_count = 0;
+    let mut pages: Vec<PageInfo> = vec![];
+    let mut continuation: Vec<(String, String)> = vec![];
+    loop {
+        let mut params = vec![
+            ("generator", "allpages"),
+            ("gapnamespace", namespace.as_str()),
+            ("gapfilterredir", "nonredirects"),
+            ("gaplimit", "max"),
+            ("prop", "info|pageprops"),
+            ("ppprop", "disambiguation|wikibase_item"),
+        ];
+        for (key, value) in [
+            ("gapfrom", from),
+            ("gapminsize", min_size.as_deref()),
+            ("gapmaxsize", max_size.as_deref()),
+        ] {
+            if let Some(value) = value {
+                params.push((key, value));
+            }
+        }
+        params.extend(
+            continuation
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+        let response = query(host, &params)?;
+
+        for page in response["query"]["pages"].as_array().into_iter().flatten() {
+            let Some(info) = PageInfo::from_page(page) else {
+                continue;
+            };
+            // The page properties of a batch can be spread over several responses
+            match pages.iter_mut().find(|page| page.title == info.title) {
+                Some(page) => {
+                    page.disambiguation |= info.disambiguation;
+                    page.item = page.item.take().or(info.item);
+                }
+                None => pages.push(info),
+            }
+        }
+        if response["batchcomplete"].as_bool() == Some(true) && !pages.is_empty() {
+            // The API lists the pages of a batch by page id
+            pages.sort_by(|a, b| a.title.cmp(&b.title));
+            batch_count += 1;
+            on_batch(std::mem::take(&mut pages));
+        }
+
+        let Some(next) = response["continue"].as_object() else {
+            break;
+        };
+        continuation = next
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+            .collect();
+    }
+    if !pages.is_empty() {
+        batch_count += 1;
+        on_batch(pages);
+    }
+    Ok(batch_count)
+}
+
+pub fn category_members(
+    host: &str,
+    category: &str,
+    namespace: i64,
+) -> Result<CategoryMembers, String> {
+    let mut members = CategoryMembers {
+        pages: vec![],
+        subcategories: vec![],
+    };
+    let mut cmcontinue: Option<String> = None;
+    loop {
+        let mut params = vec![
+            ("list", "categorymembers"),
+            ("cmtitle", category),
+            ("cmtype", "page|subcat"),
+            ("cmlimit", "max"),
+        ];
+        if let Some(token) = &cmcontinue {
+            params.push(("cmcontinue", token));
+        }
+        let response = query(host, &params)?;
+        let batch = response["query"]["categorymembers"]
+            .as_array()
+            .ok_or("Error: Unexpected response for category members.")?;
+        for member in batch {
+            let Some(title) = member["title"].as_str() else {
+                continue;
+            };
+            let ns = member["ns"].as_i64();
+            if ns == Some(namespace) {
+                members.pages.push(title.to_string());
+            }
+            if ns == Some(14) {
+                members.subcategories.push(title.to_string());
+            }
+        }
+        match response["continue"]["cmcontinue"].as_str() {
+            Some(token) => cmcontinue = Some(token.to_string()),
+            None => break,
+        }
+    }
+    Ok(members)
+}
+
+pub fn server_time(host: &str) -> Result<String, String> {
+    let response = query(host, &[("curtimestamp", "1")])?;
+    response["curtimestamp"]
+        .as_str()
+        .map(|timestamp| timestamp.to_string())
+        .ok_or("Error: Server did not return its time.".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::wiki_host;
+
+    #[test]
+    fn normalizes_titles() {
+        assert_eq!(normalize_title("howrah_Bridge"), "Howrah Bridge");
+        assert_eq!(normalize_title("S%C3%A3o  Paulo"), "São Paulo");
+    }
+
+    #[test]
+    fn fetches_random_titles() {
+        let titles = random_titles(wiki_host(), 3).unwrap();
+        assert_eq!(titles, ["Kolkata", "Howrah Bridge", "Sundarbans"]);
+    }
+
+    #[test]
+    fn follows_category_continuations() {
+        let members = category_members(wiki_host(), "Category:Rivers of India", 0).unwrap();
+        assert_eq!(members.pages, ["Ganges", "Godavari River", "Yamuna"]);
+        assert_eq!(
+            members.subcategories,
+            ["Category:Tributaries of the Ganges"]
+        );
+    }
+}
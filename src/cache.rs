@@ -0,0 +1,311 @@
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use serde_json::{json, Value};
+
+pub struct CacheConfig {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    pub enabled: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            dir: default_dir(),
+            max_bytes: 1024 * 1024 * 1024,
+            enabled: true,
+        }
+    }
+}
+
+pub fn default_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("XDG_CACHE_HOME") {
+        return PathBuf::from(dir).join("wp");
+    }
+    if let Some(dir) = env::var_os("LOCALAPPDATA") {
+        return PathBuf::from(dir).join("wp");
+    }
+    if let Some(dir) = env::var_os("HOME") {
+        return PathBuf::from(dir).join(".cache").join("wp");
+    }
+    PathBuf::from(".wp_cache")
+}
+
+static CACHE: OnceLock<CacheConfig> = OnceLock::new();
+
+// Must be called before the first article is fetched, otherwise the defaults are used
+pub fn configure(config: CacheConfig) {
+    if CACHE.set(config).is_err() {
+        panic!("Cache is already configured.");
+    }
+}
+
+fn config() -> &'static CacheConfig {
+    CACHE.get_or_init(CacheConfig::default)
+}
+
+// 64-bit FNV-1a. The file names have to stay the same from one build to the next, which
+// std's DefaultHasher doesn't promise
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Whether anything from this wiki is cached at all
+pub fn has_entries(host: &str) -> bool {
+    config().has_entries(host)
+}
+
+pub fn get(host: &str, title: &str, revid: u64) -> Option<String> {
+    config().get(host, title, revid)
+}
+
+pub fn put(host: &str, title: &str, revid: u64, raw_text: &str, fetched_at: &str) {
+    config().put(host, title, revid, raw_text, fetched_at)
+}
+
+// Evicts the least recently used entries until the cache fits in its size cap
+pub fn enforce_limit() -> io::Result<()> {
+    config().enforce_limit()
+}
+
+impl CacheConfig {
+    fn objects_dir(&self) -> PathBuf {
+        self.dir.join("objects")
+    }
+
+    // Entries are grouped by wiki, so that it is cheap to tell whether there are any for one
+    fn host_dir(&self, host: &str) -> PathBuf {
+        self.objects_dir().join(host.replace(':', "_"))
+    }
+
+    // Every revision of an article is immutable, so host + title + revision id is all we need
+    fn object_path(&self, host: &str, title: &str, revid: u64) -> PathBuf {
+        let key = format!("{}\n{}\n{}", host, title, revid);
+        self.host_dir(host)
+            .join(format!("{:016x}.json", fnv1a(key.as_bytes())))
+    }
+
+    fn has_entries(&self, host: &str) -> bool {
+        self.enabled
+            && fs::read_dir(self.host_dir(host)).is_ok_and(|mut entries| entries.next().is_some())
+    }
+
+    fn get(&self, host: &str, title: &str, revid: u64) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let path = self.object_path(host, title, revid);
+        let entry: Value = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+        if entry["host"] != host || entry["title"] != title || entry["revid"] != revid {
+            return None;
+        }
+        // The modification time doubles as the last access time for eviction
+        if let Ok(file) = File::options().append(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        entry["raw_text"]
+            .as_str()
+            .map(|raw_text| raw_text.to_string())
+    }
+
+    fn put(&self, host: &str, title: &str, revid: u64, raw_text: &str, fetched_at: &str) {
+        if !self.enabled {
+            return;
+        }
+        let path = self.object_path(host, title, revid);
+        let entry = json!({
+            "host": host,
+            "title": title,
+            "revid": revid,
+            "fetched_at": fetched_at,
+            "raw_text": raw_text,
+        });
+        // Write to a temporary file first so other threads, and other wp processes sharing
+        // the cache, never read half an entry
+        let temp_path = path.with_extension(format!(
+            "{}.{:?}.tmp",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let result = fs::create_dir_all(self.host_dir(host))
+            .and_then(|_| fs::write(&temp_path, entry.to_string()))
+            .and_then(|_| fs::rename(&temp_path, &path));
+        if let Err(err) = result {
+            println!("\x1b[31m⚠️ Could not write to the cache: {}\x1b[0m", err);
+            let _ = fs::remove_file(&temp_path);
+        }
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let dir = self.objects_dir();
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut entries = vec![];
+        for host_dir in fs::read_dir(dir)? {
+            let host_dir = host_dir?;
+            if !host_dir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(host_dir.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    entries.push((entry.path(), metadata.len(), metadata.modified()?));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn enforce_limit(&self) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let mut entries = self.entries()?;
+        let mut total_size: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        if total_size <= self.max_bytes {
+            return Ok(());
+        }
+        entries.sort_by_key(|(_, _, modified)| *modified);
+        for (path, size, _) in entries {
+            if total_size <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(path)?;
+            total_size -= size;
+        }
+        Ok(())
+    }
+}
+
+pub fn print_stats() -> io::Result<()> {
+    let entries = config().entries()?;
+    let total_size: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    println!("🗃️ Cache directory: {}", config().dir.display());
+    println!("📄 Cached revisions: {}", entries.len());
+    println!(
+        "📊 Size: {:.1} MB of {:.1} MB",
+        total_size as f64 / (1024.0 * 1024.0),
+        config().max_bytes as f64 / (1024.0 * 1024.0)
+    );
+    Ok(())
+}
+
+pub fn clear() -> io::Result<()> {
+    let dir = config().objects_dir();
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    println!("✅ Cache cleared.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // A cache of its own in the temp directory, removed again when dropped
+    struct TempCache(CacheConfig);
+
+    impl TempCache {
+        fn new(name: &str, max_bytes: u64) -> Self {
+            let dir = env::temp_dir().join(format!("wp-cache-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            TempCache(CacheConfig {
+                dir,
+                max_bytes,
+                enabled: true,
+            })
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0.dir);
+        }
+    }
+
+    #[test]
+    fn file_names_dont_depend_on_the_toolchain() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn round_trips_an_entry() {
+        let TempCache(cache) = &TempCache::new("round-trip", 1024 * 1024);
+        assert!(!cache.has_entries("en.wikipedia.org"));
+        let raw_text = r#""'''Kolkata''' is a city.\n""#;
+        cache.put(
+            "en.wikipedia.org",
+            "Kolkata",
+            42,
+            raw_text,
+            "2024-01-01T00:00:00Z",
+        );
+        assert!(cache.has_entries("en.wikipedia.org"));
+        assert_eq!(
+            cache.get("en.wikipedia.org", "Kolkata", 42).as_deref(),
+            Some(raw_text)
+        );
+        // Other revisions, titles and wikis are different entries
+        assert_eq!(cache.get("en.wikipedia.org", "Kolkata", 43), None);
+        assert_eq!(cache.get("en.wikipedia.org", "Howrah", 42), None);
+        assert_eq!(cache.get("de.wikipedia.org", "Kolkata", 42), None);
+        assert!(!cache.has_entries("de.wikipedia.org"));
+        // No temporary files are left behind
+        assert_eq!(cache.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entries_first() {
+        let TempCache(cache) = &mut TempCache::new("eviction", u64::MAX);
+        let raw_text = "x".repeat(1000);
+        let now = SystemTime::now();
+        for (revid, title) in ["Kolkata", "Howrah", "Sealdah"].iter().enumerate() {
+            cache.put("en.wikipedia.org", title, revid as u64, &raw_text, "");
+            // Kolkata is the oldest, Sealdah the newest
+            let path = cache.object_path("en.wikipedia.org", title, revid as u64);
+            File::options()
+                .append(true)
+                .open(path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(60 * (3 - revid as u64)))
+                .unwrap();
+        }
+        // Reading Kolkata makes it the most recently used
+        assert!(cache.get("en.wikipedia.org", "Kolkata", 0).is_some());
+
+        // Checking for the files, a get would count as a use
+        let is_cached = |cache: &CacheConfig, title, revid| {
+            cache.object_path("en.wikipedia.org", title, revid).exists()
+        };
+        let entry_size = cache
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|(_, size, _)| *size)
+            .max()
+            .unwrap();
+        cache.max_bytes = entry_size * 2;
+        cache.enforce_limit().unwrap();
+        assert!(!is_cached(cache, "Howrah", 1));
+        assert!(is_cached(cache, "Sealdah", 2));
+        assert!(is_cached(cache, "Kolkata", 0));
+
+        cache.max_bytes = entry_size;
+        cache.enforce_limit().unwrap();
+        assert!(!is_cached(cache, "Sealdah", 2));
+        assert!(is_cached(cache, "Kolkata", 0));
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use url::Url;
+
+use crate::api::{article_link, normalize_title, server_time};
+use crate::manifest::{record_downloads, ManifestEntry};
+use crate::site::{host_of, split_link};
+use crate::{
+    file_stem, raw_article_following_redirects, save_article, wikilink_targets, DownloadOptions,
+    RawArticle,
+};
+
+// #REDIRECT [[...]] and its translations, like #WEITERLEITUNG [[...]]
+static REDIRECT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\s*#[^\s\[]+\s*:?\s*\[\[").unwrap());
+
+fn page_key(link: &str) -> Option<String> {
+    let (host, url_title) = split_link(link).ok()?;
+    Some(format!("{}/{}", host, normalize_title(&url_title)))
+}
+
+fn article_key(article: &RawArticle) -> String {
+    format!("{}/{}", article.host, normalize_title(&article.title))
+}
+
+// Breadth-first walk over the links from the seeds, handing every article reached (once, and
+// never a redirect) over along with its depth. Returns how many were handed over
+fn walk(
+    seeds: &[String],
+    max_depth: usize,
+    max_pages: usize,
+    as_of: Option<&str>,
+    mut save: impl FnMut(&RawArticle, usize),
+) -> Result<usize, String> {
+    let mut visited = HashSet::new();
+    let mut pending = VecDeque::new();
+    for seed in seeds {
+        let key = page_key(seed).ok_or(format!("Invalid seed link: {}", seed))?;
+        if visited.insert(key) {
+            pending.push_back((seed.clone(), 0));
+        }
+    }
+
+    println!("⚡ Crawling from {} seed(s)...", pending.len());
+
+    let mut saved_count = 0;
+    while let Some((link, depth)) = pending.pop_front() {
+        if saved_count >= max_pages {
+            break;
+        }
+        let article = match raw_article_following_redirects(&link, as_of) {
+            Ok(article) => article,
+            Err(err) => {
+                println!("\x1b[31m⚠️ Skipping {}: {}\x1b[0m", link, err);
+                continue;
+            }
+        };
+        // A link through a redirect to a page that was already reached some other way
+        let key = article_key(&article);
+        if page_key(&link).as_ref() != Some(&key) && !visited.insert(key) {
+            continue;
+        }
+        // Only a broken or double redirect is still one after following it
+        if REDIRECT.is_match(&article.wikitext()) {
+            continue;
+        }
+
+        save(&article, depth);
+        saved_count += 1;
+        println!(
+            "[{}/{}] depth {}: {}",
+            saved_count,
+            max_pages,
+            depth,
+            normalize_title(&article.title)
+        );
+
+        if depth < max_depth {
+            for target in wikilink_targets(&article) {
+                let target_link = article_link(&article.host, &target);
+                if let Some(key) = page_key(&target_link) {
+                    if visited.insert(key) {
+                        pending.push_back((target_link, depth + 1));
+                    }
+                }
+            }
+        }
+    }
+    Ok(saved_count)
+}
+
+pub fn crawl(
+    seeds: &[String],
+    max_depth: usize,
+    max_pages: usize,
+    options: &DownloadOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !Path::new("wp_downloads").exists() {
+        fs::create_dir("wp_downloads")?;
+    }
+
+    let started_at = seeds
+        .first()
+        .and_then(|seed| Url::parse(seed).ok())
+        .and_then(|url| server_time(&host_of(&url)?).ok());
+    let mut downloaded = vec![];
+    let as_of = options.as_of.as_deref();
+    let saved_count = walk(seeds, max_depth, max_pages, as_of, |article, _| {
+        save_article(article, options, true);
+        downloaded.push((
+            file_stem(&article.title),
+            ManifestEntry::from_article(article),
+        ));
+    })?;
+
+    record_downloads(
+        Path::new("wp_downloads"),
+        downloaded,
+        as_of,
+        started_at.as_deref(),
+    )?;
+    println!("✅ Crawled {} articles into wp_downloads.", saved_count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::wiki_link;
+
+    #[test]
+    fn follows_redirects_and_leaves_them_out() {
+        let mut saved = vec![];
+        let saved_count = walk(&[wiki_link("Kidderpore")], 1, 10, None, |article, depth| {
+            saved.push((normalize_title(&article.title), depth))
+        })
+        .unwrap();
+
+        // [[Calcutta]] redirects to Kolkata, which is linked too, and Black Town is a broken
+        // redirect
+        assert_eq!(saved_count, 2);
+        assert_eq!(
+            saved,
+            [("Kidderpore".to_string(), 0), ("Kolkata".to_string(), 1)]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::LazyLock;
+
+use clap::ValueEnum;
+use regex::Regex;
+use similar::{ChangeTag, TextDiff};
+
+use crate::{plaintext_from_raw, raw_article_from_link, raw_article_from_revid, RawArticle};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum DiffMode {
+    Word,
+    Line,
+}
+
+// ISO 8601 like 2024-01-01T00:00:00Z, or MediaWiki's own 20240101000000
+static TIMESTAMP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\d{14}|\d{4}-\d{2}-\d{2}([T ].*)?)$").unwrap());
+
+// A revision can be given either by its id or by a point in time
+fn revid(revision: &str) -> Option<u64> {
+    if TIMESTAMP.is_match(revision) {
+        return None;
+    }
+    revision.parse().ok()
+}
+
+fn revision_from(link: &str, revision: &str) -> Result<RawArticle, String> {
+    match revid(revision) {
+        Some(revid) => raw_article_from_revid(link, revid),
+        None => raw_article_from_link(link, Some(revision)),
+    }
+}
+
+pub fn print_diff(
+    link: &str,
+    from: &str,
+    to: &str,
+    mode: DiffMode,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let old = revision_from(link, from)?;
+    let new = revision_from(link, to)?;
+    let old_text = plaintext_from_raw(&old);
+    let new_text = plaintext_from_raw(&new);
+
+    println!(
+        "\x1b[31m--- revision {} ({})\x1b[0m",
+        old.revid, old.timestamp
+    );
+    println!(
+        "\x1b[32m+++ revision {} ({})\x1b[0m\n",
+        new.revid, new.timestamp
+    );
+
+    match mode {
+        DiffMode::Line => {
+            let diff = TextDiff::from_lines(&old_text, &new_text);
+            for change in diff.iter_all_changes() {
+                let line = change.value().trim_end_matches('\n');
+                match change.tag() {
+                    ChangeTag::Delete => println!("\x1b[31m- {}\x1b[0m", line),
+                    ChangeTag::Insert => println!("\x1b[32m+ {}\x1b[0m", line),
+                    ChangeTag::Equal => println!("  {}", line),
+                }
+            }
+        }
+        DiffMode::Word => {
+            let diff = TextDiff::from_words(&old_text, &new_text);
+            let mut output = String::new();
+            for change in diff.iter_all_changes() {
+                match change.tag() {
+                    ChangeTag::Delete => {
+                        output.push_str(&format!("\x1b[9;31m{}\x1b[0m", change.value()))
+                    }
+                    ChangeTag::Insert => {
+                        output.push_str(&format!("\x1b[32m{}\x1b[0m", change.value()))
+                    }
+                    ChangeTag::Equal => output.push_str(change.value()),
+                }
+            }
+            println!("{}", output);
+        }
+    }
+
+    if old_text == new_text {
+        println!("\n✅ No changes in the text.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_revision_ids_from_timestamps() {
+        assert_eq!(revid("1221000001"), Some(1221000001));
+        assert_eq!(revid("20240101000000"), None);
+        assert_eq!(revid("2024-01-01T00:00:00Z"), None);
+        assert_eq!(revid("2024-01-01"), None);
+        assert_eq!(revid("yesterday"), None);
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+
+use crate::api::{normalize_title, page_info, PageInfo};
+use crate::site::split_link;
+use crate::wikidata::entity_claims;
+use crate::TITLES_PER_REQUEST;
+
+// Wikidata class of the "List of ..." articles, whatever the language calls them
+const LIST_ARTICLE: &str = "Q13406463";
+
+// What to leave out of the links before anything gets downloaded
+#[derive(Clone, Default)]
+pub struct LinkFilters {
+    pub include: Option<Regex>,
+    pub exclude: Option<Regex>,
+    // Namespace the pages are listed from, 0 being the articles
+    pub namespace: i64,
+    // Bounds on the size of the wikitext in bytes, like apminsize and apmaxsize
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub skip_disambiguation: bool,
+    pub skip_lists: bool,
+}
+
+impl LinkFilters {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_none() && !self.needs_page_info()
+    }
+
+    // Special:AllPages only lists titles, anything more has to come from the API
+    pub fn needs_api(&self) -> bool {
+        self.namespace != 0 || self.needs_page_info()
+    }
+
+    fn needs_page_info(&self) -> bool {
+        self.min_size.is_some()
+            || self.max_size.is_some()
+            || self.skip_disambiguation
+            || self.skip_lists
+    }
+
+    fn matches_title(&self, title: &str) -> bool {
+        self.include.as_ref().is_none_or(|re| re.is_match(title))
+            && !self.exclude.as_ref().is_some_and(|re| re.is_match(title))
+    }
+
+    fn matches_page(&self, page: &PageInfo) -> bool {
+        self.min_size.is_none_or(|min| page.length >= min)
+            && self.max_size.is_none_or(|max| page.length <= max)
+            && !(self.skip_disambiguation && page.disambiguation)
+    }
+}
+
+fn link_title(link: &str) -> Option<(String, String)> {
+    let (host, url_title) = split_link(link).ok()?;
+    let title = percent_decode_str(&url_title).decode_utf8_lossy();
+    Some((host, normalize_title(&title)))
+}
+
+// Which of the items are list articles. None of them, as far as we know, when Wikidata can't say
+fn list_items<'a>(host: &str, items: impl Iterator<Item = &'a String>) -> HashSet<String> {
+    let ids: Vec<String> = items.cloned().collect();
+    let entities = entity_claims(host, &ids).unwrap_or_else(|err| {
+        println!("\x1b[31m⚠️ {}, list articles left in\x1b[0m", err);
+        Default::default()
+    });
+    entities
+        .into_values()
+        .filter(|entity| {
+            entity
+                .instance_of
+                .iter()
+                .any(|class| class.id == LIST_ARTICLE)
+        })
+        .map(|entity| entity.id)
+        .collect()
+}
+
+// Pages listed by the API that pass the filters, in the same order
+pub fn filter_pages(host: &str, pages: Vec<PageInfo>, filters: &LinkFilters) -> Vec<PageInfo> {
+    let kept: Vec<PageInfo> = pages
+        .into_iter()
+        .filter(|page| filters.matches_title(&page.title) && filters.matches_page(page))
+        .collect();
+    if !filters.skip_lists {
+        return kept;
+    }
+    let lists = list_items(host, kept.iter().filter_map(|page| page.item.as_ref()));
+    kept.into_iter()
+        .filter(|page| !page.item.as_ref().is_some_and(|item| lists.contains(item)))
+        .collect()
+}
+
+// Filters one batch of links. Parts of it the wiki couldn't tell us about are kept as they are
+fn filter_batch(host: &str, batch: Vec<(String, String)>, filters: &LinkFilters) -> Vec<String> {
+    let titles: Vec<String> = batch.iter().map(|(_, title)| title.clone()).collect();
+    let info = match page_info(host, &titles) {
+        Ok(info) => info,
+        Err(err) => {
+            println!("\x1b[31m⚠️ {}, batch left unfiltered\x1b[0m", err);
+            return batch.into_iter().map(|(link, _)| link).collect();
+        }
+    };
+
+    let kept: Vec<(String, Option<&PageInfo>)> = batch
+        .into_iter()
+        .map(|(link, title)| (link, info.get(&title)))
+        .filter(|(_, page)| page.is_none_or(|page| filters.matches_page(page)))
+        .collect();
+    let lists = if filters.skip_lists {
+        list_items(
+            host,
+            kept.iter()
+                .filter_map(|(_, page)| page.and_then(|page| page.item.as_ref())),
+        )
+    } else {
+        HashSet::new()
+    };
+    kept.into_iter()
+        .filter(|(_, page)| {
+            !page
+                .and_then(|page| page.item.as_ref())
+                .is_some_and(|item| lists.contains(item))
+        })
+        .map(|(link, _)| link)
+        .collect()
+}
+
+// Links that pass the filters, in the same order. The title patterns are matched locally, the
+// rest asks the wiki (and Wikidata, for list articles) about a batch of titles at a time. Links
+// that come from the API in the first place go through `filter_pages` instead
+pub fn filter_links(links: Vec<String>, filters: &LinkFilters) -> Vec<String> {
+    if filters.is_empty() {
+        return links;
+    }
+    let mut kept = vec![];
+    let mut pending: Vec<(String, String)> = vec![];
+    let mut pending_host = String::new();
+    for link in links {
+        let Some((host, title)) = link_title(&link) else {
+            continue;
+        };
+        if !filters.matches_title(&title) {
+            continue;
+        }
+        if !filters.needs_page_info() {
+            kept.push(link);
+            continue;
+        }
+        if !pending.is_empty() && (host != pending_host || pending.len() == TITLES_PER_REQUEST) {
+            let batch = std::mem::take(&mut pending);
+            kept.extend(filter_batch(&pending_host, batch, filters));
+        }
+        pending_host = host;
+        pending.push((link, title));
+    }
+    if !pending.is_empty() {
+        kept.extend(filter_batch(&pending_host, pending, filters));
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::wiki_link;
+
+    #[test]
+    fn filters_by_title_size_and_page_type() {
+        let links: Vec<String> = [
+            "Bay_of_Bengal",
+            "Ganga",
+            "Howrah_Bridge",
+            "Kolkata",
+            "List_of_bridges_in_Kolkata",
+        ]
+        .iter()
+        .map(|title| wiki_link(title))
+        .collect();
+
+        let by_title = LinkFilters {
+            include: Some(Regex::new("^[A-K]").unwrap()),
+            exclude: Some(Regex::new("Bengal").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter_links(links.clone(), &by_title),
+            [
+                wiki_link("Ganga"),
+                wiki_link("Howrah_Bridge"),
+                wiki_link("Kolkata")
+            ]
+        );
+
+        let by_page = LinkFilters {
+            exclude: Some(Regex::new("^Bay").unwrap()),
+            min_size: Some(1000),
+            max_size: Some(100_000),
+            skip_disambiguation: true,
+            skip_lists: true,
+            ..Default::default()
+        };
+        assert_eq!(filter_links(links, &by_page), [wiki_link("Howrah_Bridge")]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::http::{HttpClient, WikiClient};
+use crate::site::host_of;
+
+pub const INDEX_FILE: &str = "fixtures.json";
+
+// A recorded response, served for every request to `path` that has (at least) all of `params`
+struct Fixture {
+    host: Option<String>,
+    path: String,
+    params: BTreeMap<String, String>,
+    file: String,
+    body: String,
+}
+
+impl Fixture {
+    fn matches(&self, url: &Url, params: &BTreeMap<String, String>, match_host: bool) -> bool {
+        let is_same_host = !match_host
+            || self
+                .host
+                .as_ref()
+                .is_none_or(|host| host_of(url).as_ref() == Some(host));
+        is_same_host
+            && url.path() == self.path
+            && self
+                .params
+                .iter()
+                .all(|(key, value)| params.get(key) == Some(value))
+    }
+}
+
+// A directory of recorded responses, described by the fixtures.json in it
+pub struct Fixtures {
+    dir: PathBuf,
+    fixtures: Mutex<Vec<Fixture>>,
+}
+
+impl Fixtures {
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let index_path = dir.join(INDEX_FILE);
+        let mut fixtures = vec![];
+        if index_path.exists() {
+            let index: Value = fs::read_to_string(&index_path)
+                .map_err(|err| err.to_string())
+                .and_then(|index| serde_json::from_str(&index).map_err(|err| err.to_string()))
+                .map_err(|err| format!("Error: Invalid {} due to {}", index_path.display(), err))?;
+            for entry in index.as_array().into_iter().flatten() {
+                let file = entry["file"]
+                    .as_str()
+                    .ok_or(format!("Error: Fixture without a file: {}", entry))?;
+                let body = fs::read_to_string(dir.join(file)).map_err(|err| {
+                    format!("Error: Could not read fixture {} due to {}", file, err)
+                })?;
+                fixtures.push(Fixture {
+                    host: entry["host"].as_str().map(|host| host.to_string()),
+                    path: entry["path"].as_str().unwrap_or("/").to_string(),
+                    params: entry["params"]
+                        .as_object()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                        .collect(),
+                    file: file.to_string(),
+                    body,
+                });
+            }
+        }
+        Ok(Fixtures {
+            dir: dir.to_path_buf(),
+            fixtures: Mutex::new(fixtures),
+        })
+    }
+
+    // The most specific fixture for the request, if any
+    pub fn find(&self, url: &str, match_host: bool) -> Option<String> {
+        let url = Url::parse(url).ok()?;
+        let params: BTreeMap<String, String> = url.query_pairs().into_owned().collect();
+        self.fixtures
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|fixture| fixture.matches(&url, &params, match_host))
+            .max_by_key(|fixture| fixture.params.len())
+            .map(|fixture| fixture.body.clone())
+    }
+
+    fn record(&self, url: &str, body: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|err| err.to_string())?;
+        let mut hasher = DefaultHasher::new();
+        url.as_str().hash(&mut hasher);
+        let file = format!("{:x}.body", hasher.finish());
+
+        let mut fixtures = self.fixtures.lock().unwrap();
+        fixtures.push(Fixture {
+            host: host_of(&url),
+            path: url.path().to_string(),
+            params: url.query_pairs().into_owned().collect(),
+            file: file.clone(),
+            body: body.to_string(),
+        });
+        let index: Vec<Value> = fixtures
+            .iter()
+            .map(|fixture| {
+                json!({
+                    "host": fixture.host,
+                    "path": fixture.path,
+                    "params": fixture.params,
+                    "file": fixture.file,
+                })
+            })
+            .collect();
+
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(self.dir.join(&file), body))
+            .and_then(|_| {
+                fs::write(
+                    self.dir.join(INDEX_FILE),
+                    serde_json::to_string_pretty(&index).unwrap(),
+                )
+            })
+            .map_err(|err| format!("Error: Could not record response due to {}", err))
+    }
+}
+
+// Answers every request from the recorded responses, never touching the network
+pub struct ReplayClient {
+    fixtures: Fixtures,
+}
+
+impl ReplayClient {
+    pub fn new(fixtures: Fixtures) -> Self {
+        ReplayClient { fixtures }
+    }
+}
+
+impl WikiClient for ReplayClient {
+    fn get_text(&self, url: &str) -> Result<String, String> {
+        self.fixtures
+            .find(url, true)
+            .ok_or(format!("Error: No recorded response for {}", url))
+    }
+}
+
+// Goes to the network as usual, but records every response for replaying later
+pub struct RecordingClient {
+    client: HttpClient,
+    fixtures: Fixtures,
+}
+
+impl RecordingClient {
+    pub fn new(client: HttpClient, fixtures: Fixtures) -> Self {
+        RecordingClient { client, fixtures }
+    }
+}
+
+impl WikiClient for RecordingClient {
+    fn get_text(&self, url: &str) -> Result<String, String> {
+        let body = self.client.get_text(url)?;
+        self.fixtures.record(url, &body)?;
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_most_specific_fixture() {
+        let fixtures =
+            Fixtures::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")).unwrap();
+        let api = "http://localhost/w/api.php?action=query&list=categorymembers";
+
+        let first = fixtures
+            .find(&format!("{}&cmtitle=Category:Rivers+of+India", api), false)
+            .unwrap();
+        let second = fixtures
+            .find(
+                &format!(
+                    "{}&cmtitle=Category:Rivers+of+India&cmcontinue=page|4b414e474553|1234",
+                    api
+                ),
+                false,
+            )
+            .unwrap();
+        assert_ne!(first, second);
+        assert!(second.contains("Godavari River"));
+        assert!(fixtures
+            .find(&format!("{}&cmtitle=Category:Deserts", api), false)
+            .is_none());
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use serde_json::json;
+
+use crate::api::{normalize_title, query};
+use crate::site::split_link;
+use crate::{cache, file_stem, plaintext_from_raw, RawArticle};
+
+// Revisions with content can only be fetched 50 at a time
+const REVISIONS_PER_REQUEST: usize = 50;
+
+// Writes every revision since `since` (oldest first) as one JSON object per line
+pub fn download_history(
+    link: &str,
+    limit: Option<usize>,
+    since: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (host, url_title) = split_link(link)?;
+    let (host, url_title) = (host.as_str(), url_title.as_str());
+    let title = normalize_title(url_title);
+
+    let file_name = format!("{}.history.jsonl", file_stem(url_title));
+    let mut writer = BufWriter::new(File::create(&file_name)?);
+    let mut saved_count = 0;
+    let mut rvcontinue: Option<String> = None;
+
+    println!("⚡ Fetching revisions of {}...", title);
+
+    while limit.is_none_or(|limit| saved_count < limit) {
+        let remaining = limit.map_or(REVISIONS_PER_REQUEST, |limit| limit - saved_count);
+        let rvlimit = remaining.min(REVISIONS_PER_REQUEST).to_string();
+        let mut params = vec![
+            ("prop", "revisions"),
+            ("titles", title.as_str()),
+            ("rvprop", "ids|timestamp|user|comment|size|content"),
+            ("rvslots", "*"),
+            ("rvdir", "newer"),
+            ("rvlimit", &rvlimit),
+            ("curtimestamp", "1"),
+        ];
+        if let Some(since) = since {
+            params.push(("rvstart", since));
+        }
+        if let Some(token) = &rvcontinue {
+            params.push(("rvcontinue", token));
+        }
+        let response = query(host, &params)?;
+        let page = &response["query"]["pages"][0];
+        if page["missing"].as_bool() == Some(true) {
+            return Err("Article does not exist".into());
+        }
+        // Cached revisions are looked up by the title the API gives, as in get_contents
+        let page_title = page["title"].as_str().unwrap_or(&title);
+        let fetched_at = response["curtimestamp"].as_str().unwrap_or_default();
+
+        for revision in page["revisions"].as_array().into_iter().flatten() {
+            let revid = revision["revid"].as_u64().unwrap_or_default();
+            let content = &revision["slots"]["main"]["content"];
+            // Content of deleted revisions is hidden
+            let text = if content.is_null() {
+                None
+            } else {
+                let article = RawArticle {
+                    title: url_title.to_string(),
+                    host: host.to_string(),
+                    revid,
+                    timestamp: revision["timestamp"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    raw_text: content.to_string(),
+                };
+                cache::put(host, page_title, revid, &article.raw_text, fetched_at);
+                Some(plaintext_from_raw(&article))
+            };
+            let record = json!({
+                "revid": revid,
+                "parentid": revision["parentid"],
+                "timestamp": revision["timestamp"],
+                "user": revision["user"],
+                "comment": revision["comment"],
+                "size": revision["size"],
+                "text": text,
+            });
+            writeln!(writer, "{}", record)?;
+            saved_count += 1;
+        }
+        println!("⚡ Saved {} revisions", saved_count);
+
+        match response["continue"]["rvcontinue"].as_str() {
+            Some(token) => rvcontinue = Some(token.to_string()),
+            None => break,
+        }
+    }
+
+    writer.flush()?;
+    println!("\x1B[32mSaved to {}\x1B[0m", file_name);
+    Ok(())
+}
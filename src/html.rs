@@ -0,0 +1,317 @@
+use std::sync::LazyLock;
+
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::api::api_request;
+use crate::tables::{restore_tables, span, table_marker, Cell, Table, MAX_COLSPAN, MAX_ROWSPAN};
+use crate::{generate_text, FormatType, Markup, RawArticle, Token};
+
+// Page furniture that isn't part of the text
+static SKIPPED: LazyLock<Selector> = LazyLock::new(|| {
+    Selector::parse(
+        "style, script, link, meta, .mw-editsection, .hatnote, .navbox, .navbox-styles, \
+         .vertical-navbox, .reflist, .references, .mw-references-wrap, sup.reference, \
+         .mw-cite-backlink, .infobox, .metadata, .noprint, .mw-empty-elt, .toc, #toc, \
+         .thumb, figure, .gallery, .ambox, .sistersitebox, .shortdescription, \
+         .mw-authority-control",
+    )
+    .unwrap()
+});
+static CONTENT: LazyLock<Selector> =
+    LazyLock::new(|| Selector::parse(".mw-parser-output").unwrap());
+
+// Rendered HTML of the revision, the way the wiki shows it
+pub fn article_html(article: &RawArticle) -> Result<String, String> {
+    let response = api_request(
+        &article.host,
+        "parse",
+        &[
+            ("oldid", &article.revid.to_string()),
+            ("prop", "text"),
+            ("disableeditsection", "1"),
+            ("disabletoc", "1"),
+        ],
+    )?;
+    response["parse"]["text"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("Error: No HTML for {}", article.title))
+}
+
+// Builds the same tokens the wikitext parser does, so the text renders the same way
+#[derive(Default)]
+struct Document {
+    characters: Vec<char>,
+    tokens: Vec<Token>,
+    tables: Vec<Table>,
+    // Markers of the list being walked, e.g. #* inside a bulleted list inside a numbered one
+    list_markers: String,
+}
+
+impl Document {
+    fn push(&mut self, text: &str, format: FormatType) {
+        let start = self.characters.len();
+        self.characters.extend(text.chars());
+        self.tokens.push(Token {
+            start,
+            length: self.characters.len() - start,
+            format,
+        });
+    }
+
+    fn last_is_break(&self) -> bool {
+        matches!(
+            self.tokens.last().map(|token| &token.format),
+            None | Some(FormatType::NewLine | FormatType::ListItem | FormatType::Space)
+        )
+    }
+
+    // Words and single spaces, the way the browser would show them
+    fn text(&mut self, text: &str) {
+        if text.starts_with(char::is_whitespace) && !self.last_is_break() {
+            self.push(" ", FormatType::Space);
+        }
+        let mut words = text.split_whitespace().peekable();
+        while let Some(word) = words.next() {
+            self.push(word, FormatType::PlainWord);
+            if words.peek().is_some() || text.ends_with(char::is_whitespace) {
+                self.push(" ", FormatType::Space);
+            }
+        }
+    }
+
+    fn line_break(&mut self) {
+        while matches!(
+            self.tokens.last().map(|token| &token.format),
+            Some(FormatType::Space)
+        ) {
+            self.tokens.pop();
+        }
+        if !matches!(
+            self.tokens.last().map(|token| &token.format),
+            None | Some(FormatType::NewLine)
+        ) {
+            self.push("\n", FormatType::NewLine);
+        }
+    }
+
+    // Ends the line and leaves an empty one after it, between paragraphs
+    fn block_break(&mut self) {
+        self.line_break();
+        let count = self.tokens.len();
+        if count >= 2 && !matches!(self.tokens[count - 2].format, FormatType::NewLine) {
+            self.push("\n", FormatType::NewLine);
+        }
+    }
+
+    fn walk(&mut self, element: ElementRef) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => self.text(text),
+                Node::Element(_) => {
+                    let Some(child) = ElementRef::wrap(child) else {
+                        continue;
+                    };
+                    if !SKIPPED.matches(&child) {
+                        self.element(child);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn element(&mut self, element: ElementRef) {
+        match element.value().name() {
+            name @ ("h1" | "h2" | "h3" | "h4" | "h5" | "h6") => {
+                let format = match name {
+                    "h1" | "h2" => FormatType::Title,
+                    "h3" => FormatType::Subtitle,
+                    _ => FormatType::Subsubtitle,
+                };
+                self.block_break();
+                self.push(&inline_text(element), format);
+                self.line_break();
+            }
+            "p" | "blockquote" | "pre" => {
+                self.block_break();
+                self.walk(element);
+                self.block_break();
+            }
+            "b" | "strong" => self.push(&inline_text(element), FormatType::Bold),
+            "i" | "em" | "cite" => self.push(&inline_text(element), FormatType::Italic),
+            "br" => self.line_break(),
+            "ul" | "ol" | "dl" => self.list(element),
+            "table" => {
+                let table = table_from_html(element);
+                self.block_break();
+                self.push(&table_marker(self.tables.len()), FormatType::PlainWord);
+                self.tables.push(table);
+                self.block_break();
+            }
+            _ => self.walk(element),
+        }
+    }
+
+    fn list(&mut self, element: ElementRef) {
+        let outer = self.list_markers.clone();
+        if outer.is_empty() {
+            self.block_break();
+        }
+        for item in element.child_elements() {
+            if SKIPPED.matches(&item) {
+                continue;
+            }
+            let marker = match (element.value().name(), item.value().name()) {
+                (_, "dt") => ';',
+                (_, "dd") => ':',
+                ("ol", _) => '#',
+                _ => '*',
+            };
+            self.list_markers = format!("{}{}", outer, marker);
+            self.line_break();
+            let markers = self.list_markers.clone();
+            self.push(&markers, FormatType::ListItem);
+            self.walk(item);
+        }
+        self.list_markers = outer;
+        self.line_break();
+        if self.list_markers.is_empty() {
+            self.block_break();
+        }
+    }
+}
+
+// Text of an element and everything in it, whitespace collapsed
+fn inline_text(element: ElementRef) -> String {
+    fn collect(element: ElementRef, text: &mut String) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(part) => text.push_str(part),
+                Node::Element(_) => {
+                    if let Some(child) = ElementRef::wrap(child) {
+                        if child.value().name() == "br" {
+                            text.push(' ');
+                        } else if !SKIPPED.matches(&child) && child.value().name() != "table" {
+                            collect(child, text);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut text = String::new();
+    collect(element, &mut text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn table_from_html(table: ElementRef) -> Table {
+    let caption = table
+        .child_elements()
+        .find(|child| child.value().name() == "caption")
+        .map(inline_text)
+        .filter(|caption| !caption.is_empty());
+    // Rows sit in a tbody, thead or tfoot, but not in the tables nested in the cells
+    let sections = table
+        .child_elements()
+        .filter(|child| matches!(child.value().name(), "tbody" | "thead" | "tfoot"));
+    let rows = sections
+        .flat_map(|section| section.child_elements())
+        .chain(table.child_elements())
+        .filter(|row| row.value().name() == "tr")
+        .map(|row| {
+            row.child_elements()
+                .filter(|cell| matches!(cell.value().name(), "th" | "td"))
+                .map(|cell| {
+                    let span = |name: &str, max| {
+                        cell.value().attr(name).map_or(1, |value| span(value, max))
+                    };
+                    Cell {
+                        text: inline_text(cell),
+                        is_header: cell.value().name() == "th",
+                        colspan: span("colspan", MAX_COLSPAN),
+                        rowspan: span("rowspan", MAX_ROWSPAN),
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|row| !row.is_empty())
+        .collect();
+    Table { caption, rows }
+}
+
+// Text of the rendered article in the same shape as the one from the wikitext, minus the footnotes
+pub fn html_text(html: &str, markup: Markup) -> String {
+    let parsed = Html::parse_fragment(html);
+    let root = parsed
+        .select(&CONTENT)
+        .next()
+        .unwrap_or_else(|| parsed.root_element());
+    let mut document = Document::default();
+    document.walk(root);
+
+    let text = generate_text(&document.tokens, &document.characters, markup);
+    let render = match markup {
+        Markup::Plain => Table::to_ascii,
+        Markup::Markdown => Table::to_markdown,
+        Markup::Rst => Table::to_rst,
+    };
+    restore_tables(&text, &document.tables, render)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_article_from_link;
+    use crate::test_support::wiki_link;
+
+    const HTML: &str = r##"<div class="mw-parser-output">
+<div role="note" class="hatnote navigation-not-searchable">For other uses, see <a href="/wiki/Calcutta_(disambiguation)">Calcutta (disambiguation)</a>.</div>
+<table class="infobox"><tbody><tr><th>Country</th><td>India</td></tr></tbody></table>
+<p><b>Kolkata</b> is the capital of <a href="/wiki/West_Bengal">West Bengal</a>.<sup class="reference"><a href="#cite_note-1">[1]</a></sup> It is known as the <i>City of Joy</i>.
+</p>
+<div class="mw-heading mw-heading2"><h2 id="Landmarks">Landmarks</h2><span class="mw-editsection">[<a href="/w/index.php?action=edit">edit</a>]</span></div>
+<ul><li>Victoria Memorial
+<ol><li>Gardens</li><li>Museum</li></ol></li>
+<li>Howrah Bridge</li></ul>
+<table class="wikitable"><caption>Stations</caption>
+<tbody><tr><th>Station</th><th>Passengers</th></tr>
+<tr><td>Howrah</td><td rowspan="2">1,000,000</td></tr>
+<tr><td>Sealdah</td></tr></tbody></table>
+<div class="navbox"><a href="/wiki/West_Bengal">West Bengal</a> topics</div>
+<div class="reflist"><ol class="references"><li id="cite_note-1">Census of India</li></ol></div>
+</div>"##;
+
+    #[test]
+    fn renders_html_like_wikitext() {
+        assert_eq!(
+            html_text(HTML, Markup::Markdown),
+            "**Kolkata** is the capital of West Bengal. It is known as the *City of Joy*.
+
+## Landmarks
+
+- Victoria Memorial
+  1. Gardens
+  2. Museum
+- Howrah Bridge
+
+*Stations*
+
+| Station | Passengers |
+| --- | --- |
+| Howrah | 1,000,000 |
+| Sealdah |  |"
+        );
+    }
+
+    #[test]
+    fn fetches_the_rendered_revision() {
+        let article = raw_article_from_link(&wiki_link("Kolkata"), None).unwrap();
+        let html = article_html(&article).unwrap();
+        assert_eq!(
+            html_text(&html, Markup::Plain),
+            "Kolkata (formerly Calcutta) is the capital of the Indian state of West Bengal. It lies on the eastern bank of the Hooghly River, 80 km west of the border with Bangladesh.\n\nHistory\n\nThe East India Company founded the city in 1690."
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::Client;
+use url::Url;
+
+use crate::site::host_of;
+
+pub struct HttpConfig {
+    pub user_agent: String,
+    // 0 means no limit
+    pub requests_per_second: f64,
+    pub max_per_host: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            user_agent: format!(
+                "wp/{} (https://github.com/amkhrjee/wp)",
+                env!("CARGO_PKG_VERSION")
+            ),
+            requests_per_second: 10.0,
+            max_per_host: 4,
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    last_refill: Instant,
+}
+
+// Everything that talks to a wiki goes through this, so it can be swapped out for recorded responses
+pub trait WikiClient: Send + Sync {
+    fn get_text(&self, url: &str) -> Result<String, String>;
+}
+
+pub struct HttpClient {
+    client: Client,
+    bucket: Mutex<TokenBucket>,
+    max_per_host: usize,
+    in_flight: Mutex<HashMap<String, usize>>,
+    slot_freed: Condvar,
+}
+
+// Held for the duration of a request, frees up the slot for the host when dropped
+struct HostSlot<'a> {
+    http: &'a HttpClient,
+    host: String,
+}
+
+impl Drop for HostSlot<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.http.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.host) {
+            *count -= 1;
+        }
+        self.http.slot_freed.notify_all();
+    }
+}
+
+impl HttpClient {
+    pub fn new(config: HttpConfig) -> Self {
+        let client = Client::builder()
+            .user_agent(config.user_agent)
+            .build()
+            .expect("Failed to build the HTTP client.");
+        let rate = config.requests_per_second.max(0.0);
+        HttpClient {
+            client,
+            bucket: Mutex::new(TokenBucket {
+                tokens: rate.max(1.0),
+                capacity: rate.max(1.0),
+                rate,
+                last_refill: Instant::now(),
+            }),
+            max_per_host: config.max_per_host.max(1),
+            in_flight: Mutex::new(HashMap::new()),
+            slot_freed: Condvar::new(),
+        }
+    }
+
+    fn wait_for_token(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                if bucket.rate == 0.0 {
+                    return;
+                }
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.capacity);
+                bucket.last_refill = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.rate)
+            };
+            sleep(wait);
+        }
+    }
+
+    fn acquire_slot(&self, host: &str) -> HostSlot<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight.get(host).unwrap_or(&0) >= self.max_per_host {
+            in_flight = self.slot_freed.wait(in_flight).unwrap();
+        }
+        *in_flight.entry(host.to_string()).or_default() += 1;
+        HostSlot {
+            http: self,
+            host: host.to_string(),
+        }
+    }
+}
+
+impl WikiClient for HttpClient {
+    fn get_text(&self, url: &str) -> Result<String, String> {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| host_of(&url))
+            .unwrap_or_default();
+
+        let _slot = self.acquire_slot(&host);
+        self.wait_for_token();
+        self.client
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("Error: Could not fetch {} due to {}", url, err))?
+            .text()
+            .map_err(|err| format!("Error: Could not read response due to {}", err))
+    }
+}
+
+static CLIENT: OnceLock<Box<dyn WikiClient>> = OnceLock::new();
+
+// Must be called before the first request, otherwise a client with the defaults is used
+pub fn set_client(client: Box<dyn WikiClient>) {
+    if CLIENT.set(client).is_err() {
+        panic!("HTTP client is already configured.");
+    }
+}
+
+fn client() -> &'static dyn WikiClient {
+    CLIENT
+        .get_or_init(|| Box::new(HttpClient::new(HttpConfig::default())))
+        .as_ref()
+}
+
+pub fn get_text(url: &str) -> Result<String, String> {
+    client().get_text(url)
+}
+
+pub fn get_json(url: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(&get_text(url)?)
+        .map_err(|err| format!("Error: JSON conversion failed due to {}", err))
+}
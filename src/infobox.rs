@@ -0,0 +1,68 @@
+use serde_json::{json, Value};
+
+use crate::site::profile;
+use crate::template::{inline_plaintext, templates};
+use crate::RawArticle;
+
+pub struct Infobox {
+    pub template: String,
+    // In the order they appear in the wikitext, fields without a value are left out
+    pub fields: Vec<(String, String)>,
+}
+
+// Every infobox at the top level of the article, usually there is just the one
+pub fn infoboxes(article: &RawArticle) -> Vec<Infobox> {
+    let wikitext = article.wikitext();
+    let prefixes = profile(&article.host).infobox_templates;
+    templates(&wikitext)
+        .into_iter()
+        .filter(|(_, template)| {
+            prefixes
+                .iter()
+                .any(|prefix| template.name_starts_with(prefix))
+        })
+        .map(|(_, template)| Infobox {
+            fields: template
+                .params
+                .iter()
+                .map(|(key, value)| (key.clone(), inline_plaintext(value)))
+                .filter(|(_, value)| !value.is_empty())
+                .collect(),
+            template: template.name,
+        })
+        .collect()
+}
+
+pub fn infoboxes_plaintext(infoboxes: &[Infobox]) -> String {
+    infoboxes
+        .iter()
+        .map(|infobox| {
+            let mut lines = vec![infobox.template.clone()];
+            lines.extend(
+                infobox
+                    .fields
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", key, value)),
+            );
+            lines.join("\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+// Fields are a list rather than an object so that they keep their order
+pub fn infoboxes_json(infoboxes: &[Infobox]) -> Value {
+    infoboxes
+        .iter()
+        .map(|infobox| {
+            json!({
+                "template": infobox.template,
+                "fields": infobox
+                    .fields
+                    .iter()
+                    .map(|(key, value)| json!({ "key": key, "value": value }))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect()
+}
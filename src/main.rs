@@ -1,13 +1,52 @@
-use clap::Parser;
-use scraper::bulk_download_or_save_links;
-use std::{hash::DefaultHasher, path::Path};
+use align::align;
+use api::{article_link, random_titles};
+use cache::CacheConfig;
+use clap::{Parser, Subcommand};
+use crawler::crawl;
+use diff::{print_diff, DiffMode};
+use filters::LinkFilters;
+use fixtures::{Fixtures, RecordingClient, ReplayClient};
+use history::download_history;
+use http::{HttpClient, HttpConfig};
+use meta::print_meta;
+use references::{article_references, citations, CitationFormat};
+use regex::Regex;
+use rerender::rerender;
+use sample::random_seed;
+use scraper::{bulk_download_or_save_links, download_category};
+use std::path::{Path, PathBuf};
+use tables::save_tables;
+use update::update_corpus;
 use url::Url;
 
 use core::*;
 use utils::*;
+mod align;
+mod api;
+mod cache;
 mod core;
+mod crawler;
+mod diff;
+mod filters;
+mod fixtures;
+mod history;
+mod html;
+mod http;
+mod infobox;
+mod manifest;
+mod meta;
+mod references;
+mod rerender;
+mod sample;
 mod scraper;
+mod site;
+mod tables;
+mod template;
+#[cfg(test)]
+mod test_support;
+mod update;
 mod utils;
+mod wikidata;
 
 #[derive(Parser)]
 #[command(
@@ -15,6 +54,9 @@ mod utils;
 )]
 #[command(version, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
         short,
         long,
@@ -29,33 +71,530 @@ struct Args {
 
     #[arg(long, help = "Only save the aggregated links to articles.")]
     links_only: bool,
+
+    #[arg(
+        long,
+        requires = "lang",
+        help = "Only download this many articles, sampled at random from all the links"
+    )]
+    sample: Option<usize>,
+
+    #[arg(
+        long,
+        requires = "sample",
+        help = "Seed for --sample, the same seed draws the same articles"
+    )]
+    seed: Option<u64>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Only download articles whose titles match this regex"
+    )]
+    include: Option<Regex>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Leave out articles whose titles match this regex"
+    )]
+    exclude: Option<Regex>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 0,
+        help = "Namespace to list pages from, by number (0 is the articles)"
+    )]
+    namespace: i64,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Leave out articles whose wikitext is smaller than this many bytes"
+    )]
+    min_size: Option<u64>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Leave out articles whose wikitext is larger than this many bytes"
+    )]
+    max_size: Option<u64>,
+
+    #[arg(long, global = true, help = "Leave out disambiguation pages")]
+    skip_disambiguation: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Leave out list articles, going by their Wikidata item"
+    )]
+    skip_lists: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Don't save downloaded articles whose text is shorter than this many characters"
+    )]
+    min_length: Option<usize>,
+
+    #[arg(
+        long,
+        requires = "link",
+        help = "Fetch this revision of the article instead of the latest"
+    )]
+    oldid: Option<u64>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Fetch the revisions that were current at this time, e.g. 2024-01-01T00:00:00Z"
+    )]
+    as_of: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Plaintext,
+        help = "Format of the articles"
+    )]
+    format: OutputFormat,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Also save the raw wikitext of downloaded articles, for wp rerender"
+    )]
+    keep_source: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Output the infoboxes of the articles as key/value pairs instead of their text"
+    )]
+    infobox: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        help = "Also export the references of the articles in this format, or print them instead of the text"
+    )]
+    citations: Option<CitationFormat>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Ask the wiki to expand the templates in running text that wp can't render itself"
+    )]
+    expand_templates: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = Source::Wikitext,
+        help = "Extract the text from the wikitext, or from the HTML the wiki renders"
+    )]
+    source: Source,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Add the Wikidata item of the articles and its type, coordinates and dates to JSON output"
+    )]
+    wikidata: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "User-Agent sent with every request, ideally with your contact details"
+    )]
+    user_agent: Option<String>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 10.0,
+        help = "Maximum requests per second, 0 for no limit"
+    )]
+    rate_limit: f64,
+
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 4,
+        help = "Maximum concurrent requests to a single host"
+    )]
+    max_per_host: usize,
+
+    #[arg(
+        long,
+        global = true,
+        help = "JSON file describing non-Wikimedia wikis (API path, article path, namespaces...)"
+    )]
+    site_config: Option<PathBuf>,
+
+    #[arg(long, global = true, help = "Directory to cache fetched articles in")]
+    cache_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1024,
+        help = "Maximum size of the article cache in MB"
+    )]
+    cache_size: u64,
+
+    #[arg(long, global = true, help = "Always fetch articles from the network")]
+    no_cache: bool,
+
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "replay",
+        help = "Save every response from the wikis into this directory"
+    )]
+    record: Option<PathBuf>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Answer requests from responses saved with --record instead of the network"
+    )]
+    replay: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Fetch random articles from a wikipedia language edition")]
+    Random {
+        #[arg(
+            long,
+            default_value = "en",
+            help = "Wikipedia language edition to pick from"
+        )]
+        lang: String,
+        #[arg(long, help = "Wiki to pick from instead, e.g. en.wiktionary.org")]
+        host: Option<String>,
+        #[arg(long, default_value_t = 1, help = "Number of random articles")]
+        count: usize,
+        #[arg(short, long, help = "Save articles to disk", action)]
+        save: bool,
+    },
+    #[command(about = "Collect (and optionally download) all articles in a category")]
+    Category {
+        #[arg(help = "Title of the category, e.g. \"Category:Indian mathematicians\"")]
+        category: String,
+        #[arg(
+            long,
+            default_value = "en",
+            help = "Wikipedia language edition of the category"
+        )]
+        lang: String,
+        #[arg(long, help = "Wiki of the category instead, e.g. en.wikisource.org")]
+        host: Option<String>,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "How many levels of subcategories to follow"
+        )]
+        depth: usize,
+        #[arg(long, help = "Download the articles after saving the links")]
+        download: bool,
+    },
+    #[command(about = "Crawl articles by following links from one or more seed articles")]
+    Crawl {
+        #[arg(required = true, help = "Links to the seed articles")]
+        seeds: Vec<String>,
+        #[arg(
+            long,
+            default_value_t = 1,
+            help = "How many links away from the seeds to go"
+        )]
+        depth: usize,
+        #[arg(
+            long,
+            default_value_t = 100,
+            help = "Maximum number of articles to save"
+        )]
+        max_pages: usize,
+    },
+    #[command(about = "Save every revision of an article into a JSONL file, oldest first")]
+    History {
+        #[arg(help = "Link to the wikipedia article")]
+        link: String,
+        #[arg(long, help = "Maximum number of revisions to save")]
+        limit: Option<usize>,
+        #[arg(
+            long,
+            help = "Only save revisions made after this time, e.g. 2024-01-01T00:00:00Z"
+        )]
+        since: Option<String>,
+    },
+    #[command(about = "Show what changed in the text of an article between two revisions")]
+    Diff {
+        #[arg(help = "Link to the wikipedia article")]
+        link: String,
+        #[arg(
+            long,
+            help = "Older revision, as a revision id or a time like 2024-01-01T00:00:00Z or 20240101000000"
+        )]
+        from: String,
+        #[arg(
+            long,
+            help = "Newer revision, as a revision id or a time like 2024-01-01T00:00:00Z or 20240101000000"
+        )]
+        to: String,
+        #[arg(long, value_enum, default_value_t = DiffMode::Word, help = "Compare words or lines")]
+        by: DiffMode,
+    },
+    #[command(
+        about = "Save every table of an article into a file of its own, e.g. with --format csv"
+    )]
+    Tables {
+        #[arg(help = "Link to the wikipedia article")]
+        link: String,
+    },
+    #[command(
+        about = "Write the versions of articles in several languages side by side into a JSONL file"
+    )]
+    Align {
+        #[arg(help = "Links to the articles, or their titles on the first language's Wikipedia")]
+        articles: Vec<String>,
+        #[arg(
+            long,
+            value_delimiter = ',',
+            required = true,
+            help = "Languages to align, e.g. en,hi,bn"
+        )]
+        langs: Vec<String>,
+        #[arg(
+            long,
+            help = "File with one link or title per line, e.g. a .links file"
+        )]
+        file: Option<PathBuf>,
+        #[arg(
+            long,
+            default_value = "aligned.jsonl",
+            help = "File to write the records to"
+        )]
+        output: PathBuf,
+    },
+    #[command(about = "Show the categories, interlanguage links and files of an article")]
+    Meta {
+        #[arg(help = "Link to the wikipedia article")]
+        link: String,
+    },
+    #[command(about = "Render the articles in a directory again from their saved wikitext")]
+    Rerender {
+        #[arg(help = "Directory with articles downloaded using --keep-source")]
+        dir: PathBuf,
+    },
+    #[command(about = "Bring a download directory up to date with recent changes on the wiki")]
+    Update {
+        #[arg(help = "Directory with a manifest.json, e.g. wp_downloads")]
+        dir: PathBuf,
+        #[arg(
+            long,
+            help = "Also download articles created since the last sync, drafts moved into the main namespace included"
+        )]
+        include_new: bool,
+    },
+    #[command(about = "Inspect or empty the article cache")]
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    #[command(about = "Show how much is cached and where")]
+    Stats,
+    #[command(about = "Remove every cached article")]
+    Clear,
 }
 
 fn main() {
     let args = Args::parse();
-    if args.lang.is_some() {
-        bulk_download_or_save_links(&args.lang.unwrap(), args.links_only)
-            .expect("Failed to download articles.");
-    } else if args.link.is_some() {
-        let link = args
-            .link
-            .expect("Must provide link if not bulk downloading.");
+    let mut http_config = HttpConfig {
+        requests_per_second: args.rate_limit,
+        max_per_host: args.max_per_host,
+        ..Default::default()
+    };
+    if let Some(user_agent) = args.user_agent {
+        http_config.user_agent = user_agent;
+    }
+    let http_client = HttpClient::new(http_config);
+    if let Some(dir) = &args.replay {
+        let fixtures = Fixtures::load(dir).expect("Failed to load recorded responses.");
+        http::set_client(Box::new(ReplayClient::new(fixtures)));
+    } else if let Some(dir) = &args.record {
+        let fixtures = Fixtures::load(dir).expect("Failed to load recorded responses.");
+        http::set_client(Box::new(RecordingClient::new(http_client, fixtures)));
+    } else {
+        http::set_client(Box::new(http_client));
+    }
+    if let Some(site_config) = &args.site_config {
+        site::configure(site::load_profiles(site_config).expect("Failed to load site profiles."));
+    }
+    template::configure(args.expand_templates);
+    cache::configure(CacheConfig {
+        dir: args.cache_dir.unwrap_or_else(cache::default_dir),
+        max_bytes: args.cache_size * 1024 * 1024,
+        // Cached articles would never reach the recorded responses
+        enabled: !args.no_cache && args.record.is_none() && args.replay.is_none(),
+    });
+
+    let options = DownloadOptions {
+        format: args.format,
+        keep_source: args.keep_source,
+        as_of: args.as_of,
+        infobox: args.infobox,
+        citations: args.citations,
+        source: args.source,
+        wikidata: args.wikidata,
+        min_length: args.min_length,
+    };
+    let filters = LinkFilters {
+        include: args.include,
+        exclude: args.exclude,
+        namespace: args.namespace,
+        min_size: args.min_size,
+        max_size: args.max_size,
+        skip_disambiguation: args.skip_disambiguation,
+        skip_lists: args.skip_lists,
+    };
+
+    if let Some(command) = args.command {
+        match command {
+            Command::Random {
+                lang,
+                host,
+                count,
+                save,
+            } => {
+                let host = host.unwrap_or_else(|| wikipedia_host(&lang));
+                random_articles(&host, count, save, &options)
+            }
+            Command::Category {
+                category,
+                lang,
+                host,
+                depth,
+                download,
+            } => {
+                let host = host.unwrap_or_else(|| wikipedia_host(&lang));
+                download_category(&host, &category, depth, download, &options, &filters)
+                    .expect("Failed to collect category members.")
+            }
+            Command::Crawl {
+                seeds,
+                depth,
+                max_pages,
+            } => crawl(&seeds, depth, max_pages, &options).expect("Failed to crawl articles."),
+            Command::History { link, limit, since } => {
+                download_history(&link, limit, since.as_deref())
+                    .expect("Failed to download the history.")
+            }
+            Command::Diff { link, from, to, by } => {
+                print_diff(&link, &from, &to, by).expect("Failed to diff the revisions.")
+            }
+            Command::Tables { link } => {
+                save_tables(&link, &options).expect("Failed to save the tables.")
+            }
+            Command::Align {
+                articles,
+                langs,
+                file,
+                output,
+            } => align(&articles, file.as_deref(), &langs, &output, &options)
+                .expect("Failed to align the articles."),
+            Command::Meta { link } => {
+                print_meta(&link, &options).expect("Failed to get the metadata.")
+            }
+            Command::Rerender { dir } => {
+                rerender(&dir, &options).expect("Failed to re-render articles.")
+            }
+            Command::Update { dir, include_new } => {
+                update_corpus(&dir, include_new, &options).expect("Failed to update the download.")
+            }
+            Command::Cache { action } => match action {
+                CacheAction::Stats => cache::print_stats(),
+                CacheAction::Clear => cache::clear(),
+            }
+            .expect("Failed to access the cache."),
+        }
+    } else if let Some(lang) = args.lang {
+        bulk_download_or_save_links(
+            &lang,
+            args.links_only,
+            &options,
+            &filters,
+            args.sample,
+            args.seed.unwrap_or_else(random_seed),
+        )
+        .expect("Failed to download articles.");
+    } else if let Some(link) = args.link {
         // Check if the link is a file or a url
         if Url::parse(&link).is_ok() {
-            let (plaintext, url_title) = plaintext_from_link(&link);
+            // well, if we can't get the article, just panic and quit!
+            let article = match args.oldid {
+                Some(oldid) => raw_article_from_revid(&link, oldid),
+                None => raw_article_from_link(&link, options.as_of.as_deref()),
+            }
+            .unwrap();
             if args.save {
-                let mut hasher = DefaultHasher::new();
-
-                save_to_disk(&plaintext, &url_title, &mut hasher, false);
+                save_article(&article, &options, false);
+            } else if let Some(format) = options.citations {
+                output_to_stdout(&citations(&article_references(&article), format));
             } else {
-                output_to_stdout(&plaintext);
+                output_to_stdout(&render(&article, &options));
             }
         } else if Path::new(&link).exists() {
-            download_from_file(&link);
+            download_from_file(&link, &options);
         } else {
             println!("\x1b[31m⚠️ Link provided should be either a URL or a valid file path.\x1b[0m")
         }
     } else {
         println!("\x1b[31m⚠️ Invalid arguments. Type wp --help to see all set of options.\x1b[0m")
     }
+
+    if let Err(err) = cache::enforce_limit() {
+        println!("\x1b[31m⚠️ Could not trim the cache: {}\x1b[0m", err);
+    }
+}
+
+fn wikipedia_host(lang: &str) -> String {
+    format!("{}.wikipedia.org", lang)
+}
+
+fn random_articles(host: &str, count: usize, save: bool, options: &DownloadOptions) {
+    let titles = match random_titles(host, count) {
+        Ok(titles) => titles,
+        Err(err) => {
+            println!("\x1b[31m⚠️ {}\x1b[0m", err);
+            return;
+        }
+    };
+    for title in titles {
+        let article =
+            match raw_article_from_link(&article_link(host, &title), options.as_of.as_deref()) {
+                Ok(article) => article,
+                Err(err) => {
+                    println!("\x1b[31m⚠️ {}: {}\x1b[0m", title, err);
+                    continue;
+                }
+            };
+        if save {
+            save_article(&article, options, false);
+        } else {
+            println!("\x1B[32m{}\x1B[0m\n", title);
+            output_to_stdout(&render(&article, options));
+            println!();
+        }
+    }
 }
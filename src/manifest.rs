@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+
+use crate::RawArticle;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+pub struct ManifestEntry {
+    pub title: String,
+    pub host: String,
+    pub revid: u64,
+    pub timestamp: String,
+}
+
+impl ManifestEntry {
+    pub fn from_article(article: &RawArticle) -> Self {
+        ManifestEntry {
+            title: article.title.clone(),
+            host: article.host.clone(),
+            revid: article.revid,
+            timestamp: article.timestamp.clone(),
+        }
+    }
+}
+
+// How the articles were picked when only a sample of them was downloaded
+pub struct Sample {
+    pub size: usize,
+    pub seed: u64,
+    // Number of links the sample was drawn from
+    pub population: usize,
+}
+
+impl Sample {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "size": self.size,
+            "seed": self.seed,
+            "population": self.population,
+        })
+    }
+}
+
+// Describes the articles in a download directory, keyed by the name of their output files (without the extension)
+#[derive(Default)]
+pub struct Manifest {
+    pub as_of: Option<String>,
+    // Server time up to which every article in the directory is known to be current
+    pub last_sync: Option<String>,
+    pub sample: Option<Sample>,
+    pub articles: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let manifest: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        let as_string = |value: &Value| value.as_str().map(|value| value.to_string());
+
+        let mut articles = BTreeMap::new();
+        for (stem, entry) in manifest["articles"].as_object().into_iter().flatten() {
+            articles.insert(
+                stem.clone(),
+                ManifestEntry {
+                    title: as_string(&entry["title"]).unwrap_or_default(),
+                    host: as_string(&entry["host"]).unwrap_or_default(),
+                    revid: entry["revid"].as_u64().unwrap_or_default(),
+                    timestamp: as_string(&entry["timestamp"]).unwrap_or_default(),
+                },
+            );
+        }
+
+        let sample = &manifest["sample"];
+        Ok(Manifest {
+            as_of: as_string(&manifest["as_of"]),
+            last_sync: as_string(&manifest["last_sync"]),
+            sample: sample["seed"].as_u64().map(|seed| Sample {
+                size: sample["size"].as_u64().unwrap_or_default() as usize,
+                seed,
+                population: sample["population"].as_u64().unwrap_or_default() as usize,
+            }),
+            articles,
+        })
+    }
+
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        let articles: Map<String, Value> = self
+            .articles
+            .iter()
+            .map(|(stem, entry)| {
+                (
+                    stem.clone(),
+                    json!({
+                        "title": entry.title,
+                        "host": entry.host,
+                        "revid": entry.revid,
+                        "timestamp": entry.timestamp,
+                    }),
+                )
+            })
+            .collect();
+        let mut manifest = json!({
+            "as_of": self.as_of,
+            "last_sync": self.last_sync,
+            "articles": articles,
+        });
+        if let Some(sample) = &self.sample {
+            manifest["sample"] = sample.to_json();
+        }
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            serde_json::to_string_pretty(&manifest)?,
+        )
+    }
+
+    // Only moves the sync time backwards, since articles downloaded earlier might be older.
+    // The timestamps are all ISO 8601, so comparing them as strings is enough.
+    pub fn synced_at(&mut self, timestamp: &str) {
+        match &self.last_sync {
+            Some(last_sync) if last_sync.as_str() <= timestamp => {}
+            _ => self.last_sync = Some(timestamp.to_string()),
+        }
+    }
+}
+
+pub fn record_downloads(
+    dir: &Path,
+    entries: Vec<(String, ManifestEntry)>,
+    as_of: Option<&str>,
+    started_at: Option<&str>,
+) -> io::Result<()> {
+    let mut manifest = Manifest::load(dir)?;
+    manifest.articles.extend(entries);
+    if let Some(as_of) = as_of {
+        manifest.as_of = Some(as_of.to_string());
+    }
+    if let Some(timestamp) = as_of.or(started_at) {
+        manifest.synced_at(timestamp);
+    }
+    manifest.save(dir)
+}
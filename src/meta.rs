@@ -0,0 +1,242 @@
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::site::{profile, CATEGORY_NAMESPACE, FILE_NAMESPACE};
+use crate::template::{inline_plaintext, split_top_level};
+use crate::{raw_article_from_link, DownloadOptions, OutputFormat};
+
+pub struct FileLink {
+    pub name: String,
+    pub caption: Option<String>,
+}
+
+pub struct LanguageLink {
+    pub lang: String,
+    pub title: String,
+}
+
+// The links that say something about the article rather than being part of its text
+#[derive(Default)]
+pub struct Meta {
+    pub categories: Vec<String>,
+    pub language_links: Vec<LanguageLink>,
+    pub files: Vec<FileLink>,
+}
+
+enum MetaLink {
+    Category(String),
+    Language(LanguageLink),
+    File(FileLink),
+}
+
+// Interlanguage prefixes are language codes, e.g. fr, zh-yue or be-tarask
+static LANGUAGE_PREFIX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?:[a-z]{2,3}(?:-[a-z]+)*|simple)$").unwrap());
+static SIZE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\d*(?:x\d+)?px$").unwrap());
+
+// Image options that aren't the caption, see Help:Images
+const FILE_OPTIONS: [&str; 13] = [
+    "thumb",
+    "thumbnail",
+    "frame",
+    "framed",
+    "frameless",
+    "border",
+    "right",
+    "left",
+    "center",
+    "centre",
+    "none",
+    "upright",
+    "baseline",
+];
+
+// [[links]] that aren't inside other links, with where they are in the wikitext
+fn links(wikitext: &str) -> Vec<(Range<usize>, &str)> {
+    let mut found = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut chars = wikitext.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        if c == '[' && next == Some('[') {
+            chars.next();
+            if depth == 0 {
+                start = index;
+            }
+            depth += 1;
+        } else if c == ']' && next == Some(']') && depth > 0 {
+            chars.next();
+            depth -= 1;
+            if depth == 0 {
+                found.push((start..index + 2, &wikitext[start + 2..index]));
+            }
+        }
+    }
+    found
+}
+
+fn is_file_option(option: &str) -> bool {
+    let option = option.trim();
+    let name = option.split('=').next().unwrap_or_default().trim();
+    FILE_OPTIONS.contains(&name)
+        || SIZE.is_match(option)
+        || (option.contains('=')
+            && ["alt", "link", "page", "class", "lang", "upright", "thumb"].contains(&name))
+}
+
+fn meta_link(inner: &str, host: &str) -> Option<MetaLink> {
+    let parts = split_top_level(inner, "|");
+    let target = parts.first()?.trim();
+    // [[:Category:Rivers]] and [[:fr:Calcutta]] are links in the text
+    if target.starts_with(':') {
+        return None;
+    }
+    let (prefix, rest) = target.split_once(':')?;
+    let site = profile(host);
+    let is_namespace = |number: i64, canonical: &[&str]| {
+        let prefix = prefix.trim().replace('_', " ");
+        prefix.eq_ignore_ascii_case(site.namespace(number))
+            || canonical
+                .iter()
+                .any(|name| prefix.eq_ignore_ascii_case(name))
+    };
+    let name = rest.trim().replace('_', " ");
+
+    if is_namespace(CATEGORY_NAMESPACE, &["Category"]) {
+        Some(MetaLink::Category(name))
+    } else if is_namespace(FILE_NAMESPACE, &["File", "Image"]) {
+        let caption = parts
+            .get(1..)
+            .and_then(|options| options.last())
+            .filter(|last| !is_file_option(last))
+            .map(|caption| inline_plaintext(caption))
+            .filter(|caption| !caption.is_empty());
+        Some(MetaLink::File(FileLink { name, caption }))
+    } else if LANGUAGE_PREFIX.is_match(prefix) && !name.is_empty() {
+        Some(MetaLink::Language(LanguageLink {
+            lang: prefix.to_string(),
+            title: name,
+        }))
+    } else {
+        None
+    }
+}
+
+pub fn article_meta(wikitext: &str, host: &str) -> Meta {
+    let mut meta = Meta::default();
+    for (_, inner) in links(wikitext) {
+        match meta_link(inner, host) {
+            Some(MetaLink::Category(name)) => meta.categories.push(name),
+            Some(MetaLink::Language(link)) => meta.language_links.push(link),
+            Some(MetaLink::File(file)) => meta.files.push(file),
+            None => {}
+        }
+    }
+    meta
+}
+
+// Takes the categories, interlanguage links and files out so that they don't end up in the text
+pub fn strip_meta_links(wikitext: &str, host: &str) -> String {
+    let mut text = wikitext.to_string();
+    for (range, inner) in links(wikitext).into_iter().rev() {
+        if meta_link(inner, host).is_some() {
+            text.replace_range(range, "");
+        }
+    }
+    text
+}
+
+pub fn meta_json(meta: &Meta) -> Value {
+    json!({
+        "categories": meta.categories,
+        "language_links": meta
+            .language_links
+            .iter()
+            .map(|link| json!({"lang": link.lang, "title": link.title}))
+            .collect::<Vec<_>>(),
+        "files": meta
+            .files
+            .iter()
+            .map(|file| json!({"name": file.name, "caption": file.caption}))
+            .collect::<Vec<_>>(),
+    })
+}
+
+pub fn meta_plaintext(meta: &Meta) -> String {
+    let mut lines = vec![];
+    if !meta.categories.is_empty() {
+        lines.push("Categories".to_string());
+        lines.extend(meta.categories.iter().map(|name| format!("  {}", name)));
+    }
+    if !meta.language_links.is_empty() {
+        lines.push("Language links".to_string());
+        lines.extend(
+            meta.language_links
+                .iter()
+                .map(|link| format!("  {}: {}", link.lang, link.title)),
+        );
+    }
+    if !meta.files.is_empty() {
+        lines.push("Files".to_string());
+        lines.extend(meta.files.iter().map(|file| match &file.caption {
+            Some(caption) => format!("  {}: {}", file.name, caption),
+            None => format!("  {}", file.name),
+        }));
+    }
+    lines.join("\n")
+}
+
+pub fn print_meta(link: &str, options: &DownloadOptions) -> Result<(), String> {
+    let article = raw_article_from_link(link, options.as_of.as_deref())?;
+    let meta = article_meta(&article.wikitext(), &article.host);
+    match options.format {
+        OutputFormat::Json => println!("{}", meta_json(&meta)),
+        _ => println!("{}", meta_plaintext(&meta)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIKITEXT: &str =
+        "[[File:Howrah bridge at night.jpg|thumb|upright=1.2|The [[Howrah Bridge]] at night]]
+'''Kolkata''' lies on the [[Hooghly River|Hooghly]], see also [[:Category:Rivers of India]].
+[[Image:Victoria Memorial.jpg|250px|right]]
+[[Category:Cities in India|Kolkata]]
+[[Kategorie:Ort in Westbengalen]]
+[[fr:Calcutta]]
+[[zh-yue:加爾各答]]";
+
+    #[test]
+    fn finds_categories_language_links_and_files() {
+        let meta = article_meta(WIKITEXT, "en.wikipedia.org");
+        assert_eq!(meta.categories, ["Cities in India"]);
+        let languages: Vec<(&str, &str)> = meta
+            .language_links
+            .iter()
+            .map(|link| (link.lang.as_str(), link.title.as_str()))
+            .collect();
+        assert_eq!(languages, [("fr", "Calcutta"), ("zh-yue", "加爾各答")]);
+        assert_eq!(meta.files.len(), 2);
+        assert_eq!(meta.files[0].name, "Howrah bridge at night.jpg");
+        assert_eq!(
+            meta.files[0].caption.as_deref(),
+            Some("The Howrah Bridge at night")
+        );
+        assert_eq!(meta.files[1].caption, None);
+    }
+
+    #[test]
+    fn keeps_the_text_links() {
+        assert_eq!(
+            strip_meta_links(WIKITEXT, "en.wikipedia.org"),
+            "\n'''Kolkata''' lies on the [[Hooghly River|Hooghly]], see also [[:Category:Rivers of India]].\n\n\n[[Kategorie:Ort in Westbengalen]]\n\n"
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use std::sync::LazyLock;
+
+use clap::ValueEnum;
+use regex::{Captures, Regex};
+use serde_json::{json, Map, Value};
+
+use crate::template::{inline_plaintext, templates, Template};
+use crate::RawArticle;
+
+// Stand in for the footnote markers while the text goes through the parser, which would
+// otherwise take [1] for a link
+const MARKER_START: char = '\u{E000}';
+const MARKER_END: char = '\u{E001}';
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum CitationFormat {
+    Bibtex,
+    CslJson,
+}
+
+impl CitationFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CitationFormat::Bibtex => "bib",
+            CitationFormat::CslJson => "csl.json",
+        }
+    }
+}
+
+pub struct Reference {
+    pub number: usize,
+    pub name: Option<String>,
+    // Wikitext between the ref tags, empty until a named ref used before its definition is defined
+    pub content: String,
+}
+
+impl Reference {
+    // The first {{cite ...}} template in the ref, if it has one
+    fn citation(&self) -> Option<Template> {
+        templates(&self.content)
+            .into_iter()
+            .map(|(_, template)| template)
+            .find(|template| template.name_starts_with("cite"))
+    }
+
+    // How the reference reads in a list of footnotes
+    pub fn text(&self) -> String {
+        let Some(citation) = self.citation() else {
+            return inline_plaintext(&self.content);
+        };
+        let field = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| citation.param(key))
+                .map(inline_plaintext)
+                .filter(|value| !value.is_empty())
+        };
+        let mut parts = vec![];
+        let authors = authors(&citation).join("; ");
+        match (authors.is_empty(), field(&["date", "year"])) {
+            (false, Some(date)) => parts.push(format!("{} ({})", authors, date)),
+            (false, None) => parts.push(authors),
+            (true, Some(date)) => parts.push(format!("({})", date)),
+            (true, None) => {}
+        }
+        if let Some(title) = field(&["title"]) {
+            parts.push(format!("\"{}\"", title));
+        }
+        parts.extend(field(CONTAINER_KEYS));
+        parts.extend(field(&["publisher"]));
+        parts.extend(field(&["url"]));
+        parts.join(". ")
+    }
+}
+
+const CONTAINER_KEYS: &[&str] = &["website", "work", "newspaper", "journal", "magazine"];
+
+static REF: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?s)<ref(\s[^>]*?)?(?:/>|>(.*?)</ref\s*>)"#).unwrap());
+static REF_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"name\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s/>]+))"#).unwrap());
+static ISO_DATE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(\d{4})(?:-(\d{1,2}))?(?:-(\d{1,2}))?$").unwrap());
+static YEAR: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d{4})\b").unwrap());
+static DAY: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b(\d{1,2})\b").unwrap());
+
+// Refs in order of their first use, along with the wikitext with every ref swapped for a marker
+pub fn extract_references(wikitext: &str) -> (String, Vec<Reference>) {
+    let mut references: Vec<Reference> = vec![];
+
+    let text = REF.replace_all(wikitext, |captures: &Captures| {
+        let name = captures.get(1).and_then(|attributes| {
+            REF_NAME.captures(attributes.as_str()).and_then(|name| {
+                name.get(1)
+                    .or(name.get(2))
+                    .or(name.get(3))
+                    .map(|name| name.as_str().trim().to_string())
+            })
+        });
+        let content = captures
+            .get(2)
+            .map_or("", |content| content.as_str())
+            .trim();
+
+        let existing = name.as_ref().and_then(|name| {
+            references
+                .iter_mut()
+                .find(|reference| reference.name.as_ref() == Some(name))
+        });
+        let number = match existing {
+            Some(reference) => {
+                if reference.content.is_empty() {
+                    reference.content = content.to_string();
+                }
+                reference.number
+            }
+            None => {
+                let number = references.len() + 1;
+                references.push(Reference {
+                    number,
+                    name,
+                    content: content.to_string(),
+                });
+                number
+            }
+        };
+        format!("{}{}{}", MARKER_START, number, MARKER_END)
+    });
+
+    (text.to_string(), references)
+}
+
+pub fn article_references(article: &RawArticle) -> Vec<Reference> {
+    extract_references(&article.wikitext()).1
+}
+
+// Turns the markers left in the text into footnote markers, e.g. [1] with "[" and "]"
+pub fn restore_markers(text: &str, open: &str, close: &str) -> String {
+    text.replace(MARKER_START, open).replace(MARKER_END, close)
+}
+
+pub fn footnotes_plaintext(references: &[Reference]) -> String {
+    references
+        .iter()
+        .map(|reference| format!("[{}] {}", reference.number, reference.text()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// GitHub flavoured footnotes, to go with [^1] markers
+pub fn footnotes_markdown(references: &[Reference]) -> String {
+    references
+        .iter()
+        .map(|reference| format!("[^{}]: {}", reference.number, reference.text()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// To go with [1]_ markers
+pub fn footnotes_rst(references: &[Reference]) -> String {
+    references
+        .iter()
+        .map(|reference| format!(".. [{}] {}", reference.number, reference.text()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn references_json(references: &[Reference]) -> Value {
+    references
+        .iter()
+        .map(|reference| {
+            json!({
+                "number": reference.number,
+                "name": reference.name,
+                "text": reference.text(),
+            })
+        })
+        .collect()
+}
+
+// "last1"/"first1", "last"/"first", "author1", "author" and so on
+fn authors(citation: &Template) -> Vec<String> {
+    let mut authors = vec![];
+    for index in 0..10 {
+        let suffix = if index == 0 {
+            String::new()
+        } else {
+            index.to_string()
+        };
+        let param = |key: &str| {
+            citation
+                .param(&format!("{}{}", key, suffix))
+                .map(inline_plaintext)
+                .filter(|value| !value.is_empty())
+        };
+        match (
+            param("last").or(param("surname")),
+            param("first").or(param("given")),
+        ) {
+            (Some(last), Some(first)) => authors.push(format!("{}, {}", last, first)),
+            (Some(last), None) => authors.push(last),
+            _ => authors.extend(param("author")),
+        }
+    }
+    authors
+}
+
+// Year, month and day of dates like 2011-05-07, 7 May 2011 or May 7, 2011
+fn date_parts(date: &str) -> Option<Vec<u32>> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    if let Some(captures) = ISO_DATE.captures(date.trim()) {
+        return Some(
+            captures
+                .iter()
+                .skip(1)
+                .map_while(|part| part?.as_str().parse().ok())
+                .collect(),
+        );
+    }
+    let year = YEAR.captures(date)?[1].parse().ok()?;
+    let lowercase = date.to_lowercase();
+    let month = MONTHS
+        .iter()
+        .position(|month| lowercase.contains(month))
+        .map(|month| month as u32 + 1);
+    let day = DAY.captures(date).and_then(|day| day[1].parse().ok());
+    Some(match (month, day) {
+        (Some(month), Some(day)) => vec![year, month, day],
+        (Some(month), None) => vec![year, month],
+        _ => vec![year],
+    })
+}
+
+fn csl_type(citation: Option<&Template>) -> (&'static str, &'static str) {
+    let name = citation.map_or(String::new(), |citation| citation.name.to_lowercase());
+    match name.trim_start_matches("cite ") {
+        "web" => ("webpage", "misc"),
+        "book" => ("book", "book"),
+        "journal" => ("article-journal", "article"),
+        "news" => ("article-newspaper", "article"),
+        "magazine" => ("article-magazine", "article"),
+        "thesis" => ("thesis", "phdthesis"),
+        "report" => ("report", "techreport"),
+        _ => ("document", "misc"),
+    }
+}
+
+pub fn citations_csl_json(references: &[Reference]) -> String {
+    let items: Vec<Value> = references
+        .iter()
+        .map(|reference| {
+            let citation = reference.citation();
+            let mut item = Map::new();
+            item.insert("id".to_string(), json!(format!("ref{}", reference.number)));
+            item.insert("type".to_string(), json!(csl_type(citation.as_ref()).0));
+            let Some(citation) = citation else {
+                item.insert("note".to_string(), json!(reference.text()));
+                return Value::Object(item);
+            };
+            let field = |keys: &[&str]| {
+                keys.iter()
+                    .find_map(|key| citation.param(key))
+                    .map(inline_plaintext)
+                    .filter(|value| !value.is_empty())
+            };
+            let mut insert = |key: &str, value: Option<Value>| {
+                if let Some(value) = value {
+                    item.insert(key.to_string(), value);
+                }
+            };
+            insert("title", field(&["title"]).map(Value::from));
+            insert("container-title", field(CONTAINER_KEYS).map(Value::from));
+            insert("publisher", field(&["publisher"]).map(Value::from));
+            insert(
+                "publisher-place",
+                field(&["location", "place"]).map(Value::from),
+            );
+            insert("URL", field(&["url"]).map(Value::from));
+            insert("DOI", field(&["doi"]).map(Value::from));
+            insert("ISBN", field(&["isbn"]).map(Value::from));
+            insert("volume", field(&["volume"]).map(Value::from));
+            insert("issue", field(&["issue"]).map(Value::from));
+            insert("page", field(&["pages", "page"]).map(Value::from));
+            let authors = authors(&citation);
+            if !authors.is_empty() {
+                insert(
+                    "author",
+                    Some(
+                        authors
+                            .iter()
+                            .map(|author| match author.split_once(", ") {
+                                Some((family, given)) => {
+                                    json!({ "family": family, "given": given })
+                                }
+                                None => json!({ "literal": author }),
+                            })
+                            .collect(),
+                    ),
+                );
+            }
+            for (key, keys) in [
+                ("issued", &["date", "year"][..]),
+                ("accessed", &["access-date", "accessdate"][..]),
+            ] {
+                insert(
+                    key,
+                    field(keys).map(|date| match date_parts(&date) {
+                        Some(parts) => json!({ "date-parts": [parts] }),
+                        None => json!({ "literal": date }),
+                    }),
+                );
+            }
+            Value::Object(item)
+        })
+        .collect();
+    serde_json::to_string_pretty(&items).unwrap()
+}
+
+// Braces and the like would otherwise be taken for BibTeX syntax
+fn bibtex_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\textbackslash{}")
+        .replace('{', "\\{")
+        .replace('}', "\\}")
+        .replace('&', "\\&")
+        .replace('%', "\\%")
+        .replace('#', "\\#")
+        .replace('$', "\\$")
+}
+
+pub fn citations_bibtex(references: &[Reference]) -> String {
+    references
+        .iter()
+        .map(|reference| {
+            let citation = reference.citation();
+            let entry_type = csl_type(citation.as_ref()).1;
+            let mut fields: Vec<(&str, String)> = vec![];
+            match &citation {
+                Some(citation) => {
+                    let field = |keys: &[&str]| {
+                        keys.iter()
+                            .find_map(|key| citation.param(key))
+                            .map(inline_plaintext)
+                            .filter(|value| !value.is_empty())
+                    };
+                    let authors = authors(citation);
+                    if !authors.is_empty() {
+                        fields.push(("author", authors.join(" and ")));
+                    }
+                    let container = match entry_type {
+                        "article" => "journal",
+                        "book" => "series",
+                        _ => "howpublished",
+                    };
+                    for (key, keys) in [
+                        ("title", &["title"][..]),
+                        (container, CONTAINER_KEYS),
+                        ("publisher", &["publisher"]),
+                        ("address", &["location", "place"]),
+                        ("volume", &["volume"]),
+                        ("number", &["issue"]),
+                        ("pages", &["pages", "page"]),
+                        ("doi", &["doi"]),
+                        ("isbn", &["isbn"]),
+                        ("url", &["url"]),
+                        ("urldate", &["access-date", "accessdate"]),
+                    ] {
+                        fields.extend(field(keys).map(|value| (key, value)));
+                    }
+                    if let Some(date) = field(&["date", "year"]) {
+                        match date_parts(&date).as_deref() {
+                            Some([year, rest @ ..]) => {
+                                fields.push(("year", year.to_string()));
+                                if let Some(month) = rest.first() {
+                                    fields.push(("month", month.to_string()));
+                                }
+                            }
+                            _ => fields.push(("year", date)),
+                        }
+                    }
+                }
+                None => fields.push(("note", reference.text())),
+            }
+
+            let fields: Vec<String> = fields
+                .into_iter()
+                .map(|(key, value)| match key {
+                    // URLs are taken as is by the url package
+                    "url" => format!("  {} = {{{}}}", key, value),
+                    _ => format!("  {} = {{{}}}", key, bibtex_escape(&value)),
+                })
+                .collect();
+            format!(
+                "@{}{{ref{},\n{}\n}}",
+                entry_type,
+                reference.number,
+                fields.join(",\n")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+pub fn citations(references: &[Reference], format: CitationFormat) -> String {
+    match format {
+        CitationFormat::Bibtex => citations_bibtex(references),
+        CitationFormat::CslJson => citations_csl_json(references),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIKITEXT: &str = r#"Kolkata<ref name="census">Census of India, 2011.</ref> is a city.<ref>{{cite book |last1=Dutta |first1=Krishna |title=Calcutta: A Cultural History |publisher=Signal Books |location=Oxford |date=2003 |isbn=978-1-902669-59-5}}</ref> It is big.<ref name=census /><ref name="later" /> Very big.<ref name="later">{{Cite web |url=https://example.org |title=Size & scale |website=Example |access-date=7 May 2024}}</ref>"#;
+
+    #[test]
+    fn numbers_references_by_first_use() {
+        let (text, references) = extract_references(WIKITEXT);
+        assert_eq!(
+            restore_markers(&text, "[", "]"),
+            "Kolkata[1] is a city.[2] It is big.[1][3] Very big.[3]"
+        );
+        assert_eq!(references.len(), 3);
+        assert_eq!(references[0].name.as_deref(), Some("census"));
+        // Defined after its first use
+        assert_eq!(
+            references[2].text(),
+            "\"Size & scale\". Example. https://example.org"
+        );
+        assert_eq!(
+            references[1].text(),
+            "Dutta, Krishna (2003). \"Calcutta: A Cultural History\". Signal Books"
+        );
+    }
+
+    #[test]
+    fn exports_citations() {
+        let (_, references) = extract_references(WIKITEXT);
+        let bibtex = citations_bibtex(&references);
+        assert!(bibtex.contains(
+            "@book{ref2,\n  author = {Dutta, Krishna},\n  title = {Calcutta: A Cultural History},"
+        ));
+        assert!(bibtex.contains("  title = {Size \\& scale},"));
+        assert!(bibtex.contains("@misc{ref1,\n  note = {Census of India, 2011.}\n}"));
+
+        let csl: Value = serde_json::from_str(&citations_csl_json(&references)).unwrap();
+        assert_eq!(csl[1]["type"], "book");
+        assert_eq!(csl[1]["author"][0]["family"], "Dutta");
+        assert_eq!(csl[1]["issued"]["date-parts"], json!([[2003]]));
+        assert_eq!(csl[2]["type"], "webpage");
+        assert_eq!(csl[2]["accessed"]["date-parts"], json!([[2024, 5, 7]]));
+    }
+}
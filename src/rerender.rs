@@ -0,0 +1,93 @@
+use std::fs;
+use std::path::Path;
+
+use indicatif::ProgressBar;
+use serde_json::Value;
+
+use crate::references::{article_references, citations};
+use crate::template::expands_remotely;
+use crate::{render, DownloadOptions, RawArticle, Source, SOURCE_EXTENSION};
+
+// Renders every article saved with --keep-source in the directory again, without the network
+pub fn rerender(dir: &Path, options: &DownloadOptions) -> Result<(), Box<dyn std::error::Error>> {
+    // These would fetch from the wiki for every article
+    let online: Vec<&str> = [
+        (matches!(options.source, Source::Html), "--source html"),
+        (options.wikidata, "--wikidata"),
+        (expands_remotely(), "--expand-templates"),
+    ]
+    .into_iter()
+    .filter_map(|(is_set, option)| is_set.then_some(option))
+    .collect();
+    if !online.is_empty() {
+        return Err(format!(
+            "Error: rerender only works from the saved wikitext, without the network. Leave out {}.",
+            online.join(", ")
+        )
+        .into());
+    }
+
+    let suffix = format!(".{}", SOURCE_EXTENSION);
+    let sources: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(&suffix))
+        })
+        .collect();
+
+    println!("🔍 Total sources found: {}", sources.len());
+    let bar = ProgressBar::new(sources.len().try_into().unwrap());
+    let mut failed_count = 0;
+
+    for source_path in sources {
+        bar.inc(1);
+        let source: Value = serde_json::from_str(&fs::read_to_string(&source_path)?)?;
+        let (Some(title), Some(host), Some(raw_text)) = (
+            source["title"].as_str(),
+            source["host"].as_str(),
+            source["raw_text"].as_str(),
+        ) else {
+            bar.println(format!(
+                "\x1b[31m⚠️ {} is not a valid source file\x1b[0m",
+                source_path.display()
+            ));
+            failed_count += 1;
+            continue;
+        };
+        let article = RawArticle {
+            title: title.to_string(),
+            host: host.to_string(),
+            revid: source["revid"].as_u64().unwrap_or_default(),
+            timestamp: source["timestamp"].as_str().unwrap_or_default().to_string(),
+            raw_text: raw_text.to_string(),
+        };
+
+        let rendered = render(&article, options);
+
+        let file_name = source_path.file_name().unwrap().to_str().unwrap();
+        let stem = file_name.trim_end_matches(&suffix);
+        fs::write(
+            dir.join(format!("{}.{}", stem, options.format.extension())),
+            rendered,
+        )?;
+        if let Some(format) = options.citations {
+            fs::write(
+                dir.join(format!("{}.{}", stem, format.extension())),
+                citations(&article_references(&article), format),
+            )?;
+        }
+    }
+    bar.finish_and_clear();
+
+    if failed_count > 0 {
+        println!(
+            "\x1b[31m⚠️ Could not re-render {} articles.\x1b[0m",
+            failed_count
+        );
+    }
+    println!("✅ Re-rendering complete.");
+    Ok(())
+}
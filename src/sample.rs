@@ -0,0 +1,125 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// SplitMix64, small enough to keep here so that a seed picks the same articles on every build
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in 0..bound, rejecting the values that would favour the low numbers
+    fn below(&mut self, bound: u64) -> u64 {
+        let zone = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next();
+            if value < zone {
+                return value % bound;
+            }
+        }
+    }
+}
+
+// Seed for when none was given, it ends up in the manifest either way
+pub fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+// Keeps a uniform sample of `size` links out of however many are fed in, batch after batch,
+// without holding on to all of them (Algorithm R)
+pub struct Reservoir {
+    size: usize,
+    rng: SplitMix64,
+    seen: usize,
+    // Links with their position among all the links seen
+    kept: Vec<(usize, String)>,
+}
+
+impl Reservoir {
+    pub fn new(size: usize, seed: u64) -> Self {
+        Reservoir {
+            size,
+            rng: SplitMix64(seed),
+            seen: 0,
+            kept: Vec::with_capacity(size),
+        }
+    }
+
+    pub fn add_batch(&mut self, links: &[String]) {
+        for link in links {
+            if self.kept.len() < self.size {
+                self.kept.push((self.seen, link.clone()));
+            } else {
+                let index = self.rng.below(self.seen as u64 + 1) as usize;
+                if index < self.size {
+                    self.kept[index] = (self.seen, link.clone());
+                }
+            }
+            self.seen += 1;
+        }
+    }
+
+    // How many links were sampled from
+    pub fn population(&self) -> usize {
+        self.seen
+    }
+
+    // The sampled links in the order they were gathered in
+    pub fn into_links(mut self) -> Vec<String> {
+        self.kept.sort();
+        self.kept.into_iter().map(|(_, link)| link).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(range: std::ops::Range<usize>) -> Vec<String> {
+        range
+            .map(|n| format!("https://ta.wikipedia.org/wiki/{}", n))
+            .collect()
+    }
+
+    // Links 0 up to the last end, fed in batches that stop at each of the ends
+    fn sample(batch_ends: &[usize], size: usize, seed: u64) -> Vec<String> {
+        let mut reservoir = Reservoir::new(size, seed);
+        let mut start = 0;
+        for &end in batch_ends {
+            reservoir.add_batch(&links(start..end));
+            start = end;
+        }
+        reservoir.into_links()
+    }
+
+    #[test]
+    fn samples_the_same_links_for_the_same_seed() {
+        let sampled = sample(&[300, 1000], 50, 42);
+        assert_eq!(sampled.len(), 50);
+        // How the links were split into batches doesn't matter
+        assert_eq!(sampled, sample(&[1000], 50, 42));
+        assert_ne!(sampled, sample(&[1000], 50, 43));
+        // Nothing to choose from when there are fewer links than asked for
+        assert_eq!(sample(&[10], 50, 42), links(0..10));
+    }
+
+    #[test]
+    fn picks_every_link_about_as_often() {
+        let mut counts = [0; 20];
+        for seed in 0..2000 {
+            for link in sample(&[20], 5, seed) {
+                let n: usize = link.rsplit('/').next().unwrap().parse().unwrap();
+                counts[n] += 1;
+            }
+        }
+        // Each link is expected 500 times
+        assert!(counts.iter().all(|&count| (400..600).contains(&count)));
+    }
+}
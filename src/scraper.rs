@@ -1,14 +1,21 @@
+use std::collections::{HashSet, VecDeque};
 use std::fs::{self, File};
 use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
-use reqwest::blocking::Client;
+use percent_encoding::percent_decode_str;
 use scraper::{Html, Selector};
 use url::Url;
 use zip::write::SimpleFileOptions;
 
-use crate::download_from_file;
+use crate::api::{all_pages, article_link, category_members};
+use crate::filters::{filter_links, filter_pages, LinkFilters};
+use crate::http::get_text;
+use crate::manifest::{Manifest, Sample};
+use crate::sample::Reservoir;
+use crate::site::{host_of, profile, split_link, CATEGORY_NAMESPACE};
+use crate::{download_from_file, DownloadOptions};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -20,42 +27,202 @@ struct Args {
     save: bool,
 }
 
-fn get_links(parsed_html: &Html, main_url: &str, batch_count: usize, lang: &str) -> usize {
-    println!("⚡ Starting batch {}", batch_count);
-
+// Links to all the articles listed on a page of Special:AllPages, redirects are left out
+fn links_in_page(parsed_html: &Html, origin: &str) -> Vec<String> {
     let ul_selector = Selector::parse("ul.mw-allpages-chunk").unwrap();
     let li_selector = Selector::parse("li:not([class])").unwrap();
     let a_selector = Selector::parse("a[href]").unwrap();
 
-    let links: Vec<String> = parsed_html
+    parsed_html
         .select(&ul_selector)
         .flat_map(|ul| ul.select(&li_selector))
         .flat_map(|li| li.select(&a_selector))
         .filter_map(|a| a.value().attr("href"))
-        .map(|link| format!("https://{}{}", main_url, link))
-        .collect();
+        .map(|link| format!("{}{}", origin, link))
+        .collect()
+}
+
+fn next_page_link(parsed_html: &Html, origin: &str) -> Option<String> {
+    let nav_selector = Selector::parse("div.mw-allpages-nav").unwrap();
+    let a_selector = Selector::parse("a[href]").unwrap();
+
+    parsed_html
+        .select(&nav_selector)
+        .nth(1)
+        .and_then(|div| div.select(&a_selector).nth(1))
+        .and_then(|a| a.value().attr("href"))
+        .map(|link| format!("{}{}", origin, link))
+}
+
+// Walks through Special:AllPages from `start_url`, handing over the links on every page
+fn scrape_all_pages(
+    start_url: &str,
+    mut save_batch: impl FnMut(usize, Vec<String>),
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let origin = Url::parse(start_url)?.origin().ascii_serialization();
+    let mut batch_count = 0;
+    let mut next_batch_link = start_url.to_string();
+    loop {
+        let html = get_text(&next_batch_link)?;
+        let parsed_html = Html::parse_document(&html);
+
+        batch_count += 1;
+        save_batch(batch_count, links_in_page(&parsed_html, &origin));
+
+        match next_page_link(&parsed_html, &origin) {
+            Some(next_link) => next_batch_link = next_link,
+            None => break,
+        }
+    }
+    Ok(batch_count)
+}
+
+// Title Special:AllPages starts at, either ?from= or the subpage as in Special:AllPages/A
+fn start_title(start_url: &str) -> Option<String> {
+    let url = Url::parse(start_url).ok()?;
+    if let Some((_, from)) = url.query_pairs().find(|(key, _)| key == "from") {
+        return (!from.is_empty()).then(|| from.into_owned());
+    }
+    let (_, url_title) = split_link(start_url).ok()?;
+    let title = percent_decode_str(&url_title).decode_utf8_lossy();
+    title.split_once('/').map(|(_, from)| from.to_string())
+}
+
+// Same walk as `scrape_all_pages` through the API, for when the filters need more than titles.
+// The wiki leaves out the pages outside the size bounds itself
+fn list_all_pages(
+    start_url: &str,
+    filters: &LinkFilters,
+    mut save_batch: impl FnMut(usize, Vec<String>),
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let host = host_of(&Url::parse(start_url)?).ok_or("Error: Could not get the domain.")?;
+    let from = start_title(start_url);
+    let size = (filters.min_size, filters.max_size);
+    let mut batch_count = 0;
+    all_pages(&host, filters.namespace, from.as_deref(), size, |pages| {
+        batch_count += 1;
+        let links = filter_pages(&host, pages, filters)
+            .iter()
+            .map(|page| article_link(&host, &page.title))
+            .collect();
+        save_batch(batch_count, links);
+    })?;
+    Ok(batch_count)
+}
+
+fn get_links(links: Vec<String>, batch_count: usize, lang: &str) -> usize {
+    println!("⚡ Starting batch {}", batch_count);
 
     let batch_size = links.len();
     println!("⚡ Accumulated {} links", batch_size);
 
     let file_name = format!("{}_{}.links", lang, batch_count);
-    let file = File::create(&file_name).unwrap();
+    write_links_file(&file_name, &links).unwrap();
+
+    batch_size
+}
+
+fn write_links_file(file_name: &str, links: &[String]) -> std::io::Result<()> {
+    let file = File::create(file_name)?;
     let mut writer = BufWriter::new(file);
 
     for link in &links[..links.len().saturating_sub(1)] {
-        writeln!(writer, "{}", link).unwrap();
+        writeln!(writer, "{}", link)?;
     }
 
     if let Some(last) = links.last() {
-        write!(writer, "{}", last).unwrap();
+        write!(writer, "{}", last)?;
     }
 
-    batch_size
+    writer.flush()
+}
+
+pub fn download_category(
+    host: &str,
+    category: &str,
+    depth: usize,
+    should_download: bool,
+    options: &DownloadOptions,
+    filters: &LinkFilters,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let category = if category.contains(':') {
+        category.to_string()
+    } else {
+        format!(
+            "{}:{}",
+            profile(host).namespace(CATEGORY_NAMESPACE),
+            category
+        )
+    };
+
+    println!("⚡ Walking {}...", category);
+    let (links, category_count) = category_links(host, &category, depth, filters.namespace)?;
+    let links = filter_links(links, filters);
+    println!(
+        "⚡ Accumulated {} links from {} categories",
+        links.len(),
+        category_count
+    );
+
+    let name = category
+        .split_once(':')
+        .map_or(category.as_str(), |(_, name)| name)
+        .replace([' ', '/'], "_");
+    let file_name = format!("{}_{}.links", host.replace(':', "_"), name);
+    write_links_file(&file_name, &links)?;
+    println!("✅ Links saved to {}", file_name);
+
+    if should_download {
+        println!("⚡ Proceeding with the downloads...");
+        if !Path::new("wp_downloads").exists() {
+            fs::create_dir("wp_downloads")?;
+        }
+        download_from_file(&file_name, options);
+    }
+
+    Ok(())
+}
+
+// Links to the articles in the category and its subcategories up to `depth` levels down,
+// along with the number of categories walked
+fn category_links(
+    host: &str,
+    category: &str,
+    depth: usize,
+    namespace: i64,
+) -> Result<(Vec<String>, usize), String> {
+    // Categories on wikipedia can (and do) form cycles, so keep track of the visited ones
+    let mut visited_categories = HashSet::from([category.to_string()]);
+    let mut seen_pages = HashSet::new();
+    let mut links = vec![];
+    let mut pending = VecDeque::from([(category.to_string(), 0)]);
+
+    while let Some((current, level)) = pending.pop_front() {
+        let members = category_members(host, &current, namespace)?;
+        for page in members.pages {
+            if seen_pages.insert(page.clone()) {
+                links.push(article_link(host, &page));
+            }
+        }
+        if level < depth {
+            for subcategory in members.subcategories {
+                if visited_categories.insert(subcategory.clone()) {
+                    pending.push_back((subcategory, level + 1));
+                }
+            }
+        }
+    }
+
+    Ok((links, visited_categories.len()))
 }
 
 pub fn bulk_download_or_save_links(
     lang: &str,
     is_links_only: bool,
+    options: &DownloadOptions,
+    filters: &LinkFilters,
+    sample_size: Option<usize>,
+    seed: u64,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let start_url = match lang {
         "as" => "https://as.wikipedia.org/wiki/%E0%A6%AC%E0%A6%BF%E0%A6%B6%E0%A7%87%E0%A6%B7:%E0%A6%B8%E0%A6%95%E0%A6%B2%E0%A7%8B%E0%A6%AC%E0%A7%8B%E0%A7%B0_%E0%A6%AA%E0%A7%83%E0%A6%B7%E0%A7%8D%E0%A6%A0%E0%A6%BE/%E0%A6%85",
@@ -76,61 +243,77 @@ pub fn bulk_download_or_save_links(
         _ => return Err("Unsupported language".into()),
     };
 
-    let client = Client::new();
     let mut links_count = 0;
-    let mut batch_count = 0;
-
-    let url = Url::parse(start_url)?;
-    let main_url = url.host_str().ok_or("Invalid URL")?;
+    let mut reservoir = sample_size.map(|size| Reservoir::new(size, seed));
 
     println!("⚡ Scraping links...");
 
-    let mut next_batch_link = start_url.to_string();
-    loop {
-        let response = client.get(&next_batch_link).send()?;
-        let html = response.text()?;
-        let parsed_html = Html::parse_document(&html);
-
-        batch_count += 1;
-        links_count += get_links(&parsed_html, main_url, batch_count, lang);
-
-        let nav_selector = Selector::parse("div.mw-allpages-nav").unwrap();
-        let a_selector = Selector::parse("a[href]").unwrap();
-
-        if let Some(next_link) = parsed_html
-            .select(&nav_selector)
-            .nth(1)
-            .and_then(|div| div.select(&a_selector).nth(1))
-            .and_then(|a| a.value().attr("href"))
-            .map(|link| format!("https://{}{}", main_url, link))
-        {
-            next_batch_link = next_link;
-        } else {
-            break;
+    let save_batch = |batch_count, links: Vec<String>| {
+        if let Some(reservoir) = &mut reservoir {
+            reservoir.add_batch(&links);
         }
-    }
+        links_count += get_links(links, batch_count, lang);
+    };
+    let batch_count = if filters.needs_api() {
+        list_all_pages(start_url, filters, save_batch)?
+    } else {
+        let mut save_batch = save_batch;
+        scrape_all_pages(start_url, |batch_count, links| {
+            save_batch(batch_count, filter_links(links, filters))
+        })?
+    };
     println!("✅ All links saved.");
 
+    // The batches stay as they are, only the sample gets downloaded. It isn't a .links file so
+    // that a later run without --sample doesn't take it for one more batch
+    let sample_file = format!("{}.sample", lang);
+    let sample = reservoir.map(|reservoir| {
+        let sample = Sample {
+            size: sample_size.unwrap_or_default(),
+            seed,
+            population: reservoir.population(),
+        };
+        let links = reservoir.into_links();
+        println!(
+            "🎲 Sampled {} of {} links with seed {}",
+            links.len(),
+            sample.population,
+            seed
+        );
+        (links, sample)
+    });
+    if let Some((links, _)) = &sample {
+        write_links_file(&sample_file, links)?;
+        println!("✅ Sample saved to {}", sample_file);
+    }
+
     if is_links_only {
         println!("🗃️ Zipping up all the links...");
         let zip_file = File::create(format!("{}.zip", lang))?;
         let mut zip = zip::ZipWriter::new(zip_file);
 
+        let zip_options =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
         for entry in std::fs::read_dir(".")? {
             let entry = entry?;
             let path = entry.path();
             if path.extension().and_then(|s| s.to_str()) == Some("links") {
                 let file_name = &path.file_name().unwrap().to_str().unwrap();
-                zip.start_file(
-                    file_name,
-                    SimpleFileOptions::default()
-                        .compression_method(zip::CompressionMethod::Deflated),
-                )?;
+                zip.start_file(file_name, zip_options)?;
                 let contents = std::fs::read(&path)?;
                 zip.write_all(&contents)?;
                 std::fs::remove_file(path)?;
             }
         }
+        // The sample goes along with how it was drawn, so that it can be drawn again
+        if let Some((_, sample)) = &sample {
+            zip.start_file(&sample_file, zip_options)?;
+            zip.write_all(&std::fs::read(&sample_file)?)?;
+            std::fs::remove_file(&sample_file)?;
+            zip.start_file("sample.json", zip_options)?;
+            zip.write_all(serde_json::to_string_pretty(&sample.to_json())?.as_bytes())?;
+        }
 
         zip.finish()?;
     } else {
@@ -141,23 +324,32 @@ pub fn bulk_download_or_save_links(
             Err(err) => println!("Error creating directory: {}", err),
         }
         let dir_path = Path::new(".");
-        let files: Vec<_> = fs::read_dir(dir_path)?
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| {
-                entry.path().is_file()
-                    && entry.path().extension().and_then(|s| s.to_str()) == Some("links")
-            })
-            .collect();
+        let files: Vec<PathBuf> = match &sample {
+            Some(_) => vec![PathBuf::from(&sample_file)],
+            None => fs::read_dir(dir_path)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("links")
+                })
+                .collect(),
+        };
         let mut batch_count = 0;
-        for each_file in files {
-            let file_path = each_file.path();
+        for file_path in files {
             batch_count += 1;
             println!("\x1B[32mDownloading Batch No. {}\x1B[0m", batch_count);
-            match download_from_file(file_path.to_str().unwrap()) {
+            match download_from_file(file_path.to_str().unwrap(), options) {
                 Some(_) => continue,
                 None => continue,
             }
         }
+        // Whoever gets the directory can draw the same sample again
+        if let Some((_, sample)) = sample {
+            let downloads = Path::new("wp_downloads");
+            let mut manifest = Manifest::load(downloads)?;
+            manifest.sample = Some(sample);
+            manifest.save(downloads)?;
+        }
     }
 
     println!("📊 Total batches done: {}", batch_count);
@@ -165,3 +357,80 @@ pub fn bulk_download_or_save_links(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{wiki_host, wiki_link};
+
+    #[test]
+    fn scrapes_every_page_of_all_pages() {
+        let mut batches = vec![];
+        let batch_count = scrape_all_pages(&wiki_link("Special:AllPages"), |count, links| {
+            batches.push((count, links))
+        })
+        .unwrap();
+
+        assert_eq!(batch_count, 2);
+        // Redirects are left out
+        assert_eq!(
+            batches,
+            [
+                (1, vec![wiki_link("Bay_of_Bengal"), wiki_link("Ganges")]),
+                (2, vec![wiki_link("Howrah_Bridge"), wiki_link("Kolkata")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_pages_through_the_api_for_page_filters() {
+        let filters = LinkFilters {
+            min_size: Some(1000),
+            skip_disambiguation: true,
+            skip_lists: true,
+            ..Default::default()
+        };
+        let mut batches = vec![];
+        let batch_count = list_all_pages(
+            &wiki_link("Special:AllPages/B"),
+            &filters,
+            |count, links| batches.push((count, links)),
+        )
+        .unwrap();
+
+        assert_eq!(batch_count, 2);
+        assert_eq!(
+            batches,
+            [
+                (1, vec![wiki_link("Bay_of_Bengal")]),
+                (2, vec![wiki_link("Howrah_Bridge")]),
+            ]
+        );
+        assert_eq!(
+            start_title("https://en.wikipedia.org/wiki/Special:AllPages?from=A&to=&namespace=0")
+                .as_deref(),
+            Some("A")
+        );
+    }
+
+    #[test]
+    fn walks_categories_without_looping() {
+        let category = "Category:Rivers of India";
+        let (links, category_count) = category_links(wiki_host(), category, 0, 0).unwrap();
+        assert_eq!(category_count, 1);
+        assert_eq!(links.len(), 3);
+
+        // The subcategory links back to its parent
+        let (links, category_count) = category_links(wiki_host(), category, 5, 0).unwrap();
+        assert_eq!(category_count, 2);
+        assert_eq!(
+            links,
+            [
+                wiki_link("Ganges"),
+                wiki_link("Godavari_River"),
+                wiki_link("Yamuna"),
+                wiki_link("Gomti_River"),
+            ]
+        );
+    }
+}
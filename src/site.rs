@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::Value;
+use url::{Position, Url};
+
+// Characters that can't appear as is in the title part of a link
+const TITLE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'+')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// Where things live on a MediaWiki install, Wikimedia wikis all share the defaults
+#[derive(Clone)]
+pub struct SiteProfile {
+    pub host: String,
+    pub scheme: String,
+    pub api_path: String,
+    // $1 stands for the title, like $wgArticlePath in MediaWiki
+    pub article_path: String,
+    // Local names of the namespaces we care about, by namespace number
+    pub namespaces: BTreeMap<i64, String>,
+    // Everything from a section with one of these names onwards is dropped from the text
+    pub reference_sections: Vec<String>,
+    // Templates whose names start with one of these are infoboxes
+    pub infobox_templates: Vec<String>,
+    // Wikibase repository the pages are linked to, None if there is none
+    pub wikidata_host: Option<String>,
+}
+
+pub const FILE_NAMESPACE: i64 = 6;
+pub const CATEGORY_NAMESPACE: i64 = 14;
+
+// What the sections holding only the references are usually called on the Wikipedias
+fn default_reference_sections(host: &str) -> Vec<String> {
+    let sections: &[&str] = match host.strip_suffix(".wikipedia.org") {
+        Some("en") => &["References"],
+        Some("de") => &["Einzelnachweise"],
+        Some("fr") => &["Notes et références", "Références"],
+        Some("hi") => &["सन्दर्भ"],
+        Some("bn") => &["তথ্যসূত্র"],
+        _ => &[],
+    };
+    sections.iter().map(|section| section.to_string()).collect()
+}
+
+impl SiteProfile {
+    pub fn default_for(host: &str) -> Self {
+        SiteProfile {
+            host: host.to_string(),
+            scheme: "https".to_string(),
+            api_path: "/w/api.php".to_string(),
+            article_path: "/wiki/$1".to_string(),
+            namespaces: BTreeMap::from([
+                (FILE_NAMESPACE, "File".to_string()),
+                (CATEGORY_NAMESPACE, "Category".to_string()),
+            ]),
+            reference_sections: default_reference_sections(host),
+            infobox_templates: vec!["Infobox".to_string()],
+            wikidata_host: Some("www.wikidata.org".to_string()),
+        }
+    }
+
+    fn from_json(profile: &Value) -> Option<Self> {
+        let mut site = SiteProfile::default_for(profile["host"].as_str()?);
+        if let Some(scheme) = profile["scheme"].as_str() {
+            site.scheme = scheme.to_string();
+        }
+        if let Some(api_path) = profile["api_path"].as_str() {
+            site.api_path = api_path.to_string();
+        }
+        if let Some(article_path) = profile["article_path"].as_str() {
+            site.article_path = article_path.to_string();
+        }
+        for (number, name) in profile["namespaces"].as_object().into_iter().flatten() {
+            if let (Ok(number), Some(name)) = (number.parse(), name.as_str()) {
+                site.namespaces.insert(number, name.to_string());
+            }
+        }
+        if let Some(sections) = profile["reference_sections"].as_array() {
+            site.reference_sections = sections
+                .iter()
+                .filter_map(|section| section.as_str())
+                .map(|section| section.to_string())
+                .collect();
+        }
+        if let Some(templates) = profile["infobox_templates"].as_array() {
+            site.infobox_templates = templates
+                .iter()
+                .filter_map(|template| template.as_str())
+                .map(|template| template.to_string())
+                .collect();
+        }
+        match &profile["wikidata_host"] {
+            Value::String(host) => site.wikidata_host = Some(host.clone()),
+            Value::Null if profile.get("wikidata_host").is_some() => site.wikidata_host = None,
+            _ => {}
+        }
+        Some(site)
+    }
+
+    pub fn api_url(&self) -> String {
+        format!("{}://{}{}", self.scheme, self.host, self.api_path)
+    }
+
+    // Link to an article from its title as is, e.g. "Indian mathematicians"
+    pub fn article_url(&self, title: &str) -> String {
+        let encoded = utf8_percent_encode(&title.replace(' ', "_"), TITLE).to_string();
+        self.article_url_encoded(&encoded)
+    }
+
+    // Link to an article from a title that is already encoded for use in links
+    pub fn article_url_encoded(&self, url_title: &str) -> String {
+        format!(
+            "{}://{}{}",
+            self.scheme,
+            self.host,
+            self.article_path.replace("$1", url_title)
+        )
+    }
+
+    // The encoded title part of a link to an article on this site
+    pub fn title_from_url(&self, url: &Url) -> Option<String> {
+        let (path, query) = self
+            .article_path
+            .split_once("$1")
+            .map(|(prefix, _)| prefix.split_once('?').unwrap_or((prefix, "")))?;
+        let url_title = if query.is_empty() {
+            url.path().strip_prefix(path)?
+        } else {
+            // e.g. /index.php?title=$1
+            let key = query.trim_end_matches('=');
+            url.query()?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))?
+        };
+        (!url_title.is_empty()).then(|| url_title.to_string())
+    }
+
+    pub fn namespace(&self, number: i64) -> &str {
+        self.namespaces
+            .get(&number)
+            .map_or("", |name| name.as_str())
+    }
+}
+
+static PROFILES: OnceLock<Vec<SiteProfile>> = OnceLock::new();
+
+// Must be called before the first request, otherwise every site gets the defaults
+pub fn configure(profiles: Vec<SiteProfile>) {
+    if PROFILES.set(profiles).is_err() {
+        panic!("Site profiles are already configured.");
+    }
+}
+
+// Reads a JSON list of profiles, only "host" is required in each of them
+pub fn load_profiles(path: &Path) -> Result<Vec<SiteProfile>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Error: Could not read {} due to {}", path.display(), err))?;
+    let profiles: Value = serde_json::from_str(&contents)
+        .map_err(|err| format!("Error: Invalid site profiles due to {}", err))?;
+    profiles
+        .as_array()
+        .ok_or("Error: Site profiles should be a list.")?
+        .iter()
+        .map(|profile| {
+            SiteProfile::from_json(profile)
+                .ok_or(format!("Error: Site profile without a host: {}", profile))
+        })
+        .collect()
+}
+
+pub fn profile(host: &str) -> SiteProfile {
+    PROFILES
+        .get_or_init(Vec::new)
+        .iter()
+        .find(|profile| profile.host == host)
+        .cloned()
+        .unwrap_or_else(|| SiteProfile::default_for(host))
+}
+
+// Host of the link, with the port if it has one
+pub fn host_of(url: &Url) -> Option<String> {
+    url.host_str()?;
+    Some(url[Position::BeforeHost..Position::AfterPort].to_string())
+}
+
+// Splits a link to an article into its host and (still encoded) title
+pub fn split_link(link: &str) -> Result<(String, String), String> {
+    let url = Url::parse(link).map_err(|err| format!("Error: Invalid URL due to {}", err))?;
+    let host = host_of(&url).ok_or("Error: Could not get the domain.")?;
+    let url_title = profile(&host)
+        .title_from_url(&url)
+        .ok_or("Error: Could not get parse name of the article.")?;
+    Ok((host, url_title))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_titles_in_links() {
+        let wikipedia = SiteProfile::default_for("en.wikipedia.org");
+        let url = Url::parse("https://en.wikipedia.org/wiki/Howrah_Bridge").unwrap();
+        assert_eq!(wikipedia.title_from_url(&url).unwrap(), "Howrah_Bridge");
+        let url = Url::parse("https://en.wikipedia.org/w/index.php?title=Kolkata").unwrap();
+        assert_eq!(wikipedia.title_from_url(&url), None);
+
+        let self_hosted = SiteProfile {
+            article_path: "/index.php?title=$1".to_string(),
+            ..SiteProfile::default_for("wiki.example.org")
+        };
+        let url =
+            Url::parse("https://wiki.example.org/index.php?action=view&title=Main_Page").unwrap();
+        assert_eq!(self_hosted.title_from_url(&url).unwrap(), "Main_Page");
+        assert_eq!(
+            self_hosted.article_url("Main Page"),
+            "https://wiki.example.org/index.php?title=Main_Page"
+        );
+    }
+
+    #[test]
+    fn trims_references_only_on_the_wikipedias() {
+        let sections = |host| SiteProfile::default_for(host).reference_sections;
+        assert_eq!(sections("de.wikipedia.org"), ["Einzelnachweise"]);
+        assert_eq!(sections("bn.wikipedia.org"), ["তথ্যসূত্র"]);
+        assert!(sections("en.wiktionary.org").is_empty());
+        assert!(sections("de.wikivoyage.org").is_empty());
+        assert!(sections("wiki.example.org").is_empty());
+    }
+}
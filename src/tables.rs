@@ -0,0 +1,639 @@
+use std::fs;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::template::{inline_plaintext, split_top_level};
+use crate::{file_stem, raw_article_from_link, DownloadOptions, OutputFormat, RawArticle};
+
+// Stand in for the tables while the text goes through the parser, like the footnote markers
+const MARKER_START: char = '\u{E002}';
+const MARKER_END: char = '\u{E003}';
+
+pub struct Cell {
+    pub text: String,
+    pub is_header: bool,
+    pub colspan: usize,
+    pub rowspan: usize,
+}
+
+pub struct Table {
+    pub caption: Option<String>,
+    pub rows: Vec<Vec<Cell>>,
+}
+
+// The caps MediaWiki (and browsers) put on spans
+pub const MAX_COLSPAN: usize = 1000;
+pub const MAX_ROWSPAN: usize = 65534;
+
+// The span in a colspan or rowspan attribute, capped at `max`, anything that isn't a positive number is 1
+pub fn span(value: &str, max: usize) -> usize {
+    let value = value.trim();
+    let digits =
+        &value[..value.len() - value.trim_start_matches(|c: char| c.is_ascii_digit()).len()];
+    match digits.parse::<usize>() {
+        Ok(0) => 1,
+        Ok(span) => span.min(max),
+        // Too many digits for a usize is still a huge span
+        Err(_) if !digits.is_empty() => max,
+        Err(_) => 1,
+    }
+}
+
+static COLSPAN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)colspan\s*=\s*["']?\s*(\d+)"#).unwrap());
+static ROWSPAN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)rowspan\s*=\s*["']?\s*(\d+)"#).unwrap());
+
+impl Cell {
+    // `cell` is everything after the ! or | that starts it, e.g. colspan="2" | ''Total''
+    fn parse(cell: &str, is_header: bool) -> Self {
+        let parts = split_top_level(cell, "|");
+        let (attributes, content) = match parts.split_first() {
+            Some((attributes, rest)) if !rest.is_empty() => (*attributes, rest.join("|")),
+            _ => ("", cell.to_string()),
+        };
+        let span = |re: &Regex, max| {
+            re.captures(attributes)
+                .map_or(1, |captures| span(&captures[1], max))
+        };
+        // Tables inside cells are too much, only their surroundings are kept
+        let mut content = content;
+        for (range, _) in tables(&content).into_iter().rev() {
+            content.replace_range(range, "");
+        }
+        Cell {
+            text: inline_plaintext(&content),
+            is_header,
+            colspan: span(&COLSPAN, MAX_COLSPAN),
+            rowspan: span(&ROWSPAN, MAX_ROWSPAN),
+        }
+    }
+}
+
+impl Table {
+    // `lines` are the ones between {| and |}
+    fn parse(lines: &[&str]) -> Self {
+        let mut caption = None;
+        let mut rows: Vec<Vec<(String, bool)>> = vec![];
+        let mut row: Vec<(String, bool)> = vec![];
+        let mut depth = 0;
+
+        for line in lines {
+            let trimmed = line.trim_start();
+            // Nested tables go into the cell they are in, as is
+            if depth > 0 || trimmed.starts_with("{|") {
+                if trimmed.starts_with("{|") {
+                    depth += 1;
+                } else if trimmed.starts_with("|}") {
+                    depth -= 1;
+                }
+                if let Some((text, _)) = row.last_mut() {
+                    text.push('\n');
+                    text.push_str(line);
+                }
+                continue;
+            }
+
+            if let Some(rest) = trimmed.strip_prefix("|+") {
+                caption = split_top_level(rest, "|")
+                    .last()
+                    .map(|text| inline_plaintext(text));
+            } else if trimmed.starts_with("|-") {
+                if !row.is_empty() {
+                    rows.push(std::mem::take(&mut row));
+                }
+            } else if let Some(rest) = trimmed.strip_prefix('!') {
+                // Header cells can be separated with || as well
+                for cell in split_top_level(rest, "!!") {
+                    row.extend(
+                        split_top_level(cell, "||")
+                            .into_iter()
+                            .map(|cell| (cell.to_string(), true)),
+                    );
+                }
+            } else if let Some(rest) = trimmed.strip_prefix('|') {
+                row.extend(
+                    split_top_level(rest, "||")
+                        .into_iter()
+                        .map(|cell| (cell.to_string(), false)),
+                );
+            } else if let Some((text, _)) = row.last_mut() {
+                // The content of a cell can go on for several lines
+                text.push('\n');
+                text.push_str(line);
+            }
+        }
+        if !row.is_empty() {
+            rows.push(row);
+        }
+
+        Table {
+            caption: caption.filter(|caption| !caption.is_empty()),
+            rows: rows
+                .into_iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|(cell, is_header)| Cell::parse(cell, *is_header))
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    // Rows of equal length, with the cells spanning several rows or columns covering each of
+    // the positions they span
+    pub fn grid(&self) -> Vec<Vec<Slot<'_>>> {
+        let mut grid: Vec<Vec<Slot>> = vec![];
+        // Cells from rows above still spanning down, by column
+        let mut pending: Vec<Option<(Slot, usize)>> = vec![];
+
+        for (row_index, row) in self.rows.iter().enumerate() {
+            let mut grid_row: Vec<Slot> = vec![];
+            let mut cells = row.iter();
+            let mut column = 0;
+            loop {
+                if let Some(Some((slot, remaining))) = pending.get_mut(column) {
+                    grid_row.push(*slot);
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        pending[column] = None;
+                    }
+                    column += 1;
+                    continue;
+                }
+                let Some(cell) = cells.next() else {
+                    // Rows spanning into the end of a shorter row
+                    if pending.iter().skip(column).any(|slot| slot.is_some()) {
+                        grid_row.push(Slot::empty(row_index, column));
+                        column += 1;
+                        continue;
+                    }
+                    break;
+                };
+                let slot = Slot {
+                    cell,
+                    origin: (row_index, column),
+                };
+                for _ in 0..cell.colspan {
+                    grid_row.push(slot);
+                    if cell.rowspan > 1 {
+                        if pending.len() <= column {
+                            pending.resize(column + 1, None);
+                        }
+                        pending[column] = Some((slot, cell.rowspan - 1));
+                    }
+                    column += 1;
+                }
+            }
+            grid.push(grid_row);
+        }
+
+        let width = grid.iter().map(|row| row.len()).max().unwrap_or_default();
+        for (row_index, row) in grid.iter_mut().enumerate() {
+            while row.len() < width {
+                row.push(Slot::empty(row_index, row.len()));
+            }
+        }
+        grid
+    }
+
+    // The text of every cell in the first position it covers, the others are left empty
+    fn grid_text(&self) -> Vec<Vec<String>> {
+        self.grid()
+            .iter()
+            .enumerate()
+            .map(|(row_index, row)| {
+                row.iter()
+                    .enumerate()
+                    .map(|(column, slot)| {
+                        if slot.origin == (row_index, column) {
+                            slot.cell.text.clone()
+                        } else {
+                            String::new()
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // The first row, if it is all header cells
+    fn has_header(&self) -> bool {
+        self.grid()
+            .first()
+            .is_some_and(|row| !row.is_empty() && row.iter().all(|slot| slot.cell.is_header))
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Plaintext => self.to_ascii(),
+            OutputFormat::Markdown => self.to_markdown(),
+            OutputFormat::Rst => self.to_rst(),
+            OutputFormat::Json => serde_json::to_string_pretty(&self.to_json()).unwrap(),
+            OutputFormat::Csv => self.to_csv(),
+            OutputFormat::Tsv => self.to_tsv(),
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        self.grid_text()
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|text| {
+                        if text.contains([',', '"', '\n']) {
+                            format!("\"{}\"", text.replace('"', "\"\""))
+                        } else {
+                            text.clone()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+            .map(|line| line + "\n")
+            .collect()
+    }
+
+    pub fn to_tsv(&self) -> String {
+        self.grid_text()
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|text| text.replace(['\t', '\n'], " "))
+                    .collect::<Vec<_>>()
+                    .join("\t")
+            })
+            .map(|line| line + "\n")
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut rows = self.grid_text();
+        let header = if self.has_header() && !rows.is_empty() {
+            Some(rows.remove(0))
+        } else {
+            None
+        };
+        json!({
+            "caption": self.caption,
+            "header": header,
+            "rows": rows,
+        })
+    }
+
+    // Markdown needs a header row, so the first row is used even if it isn't one
+    pub fn to_markdown(&self) -> String {
+        let rows = self.grid_text();
+        let Some((first, rest)) = rows.split_first() else {
+            return String::new();
+        };
+        let line = |row: &[String]| {
+            let cells: Vec<String> = row
+                .iter()
+                .map(|text| text.replace('|', "\\|").replace('\n', " "))
+                .collect();
+            format!("| {} |", cells.join(" | "))
+        };
+        let mut lines = vec![];
+        if let Some(caption) = &self.caption {
+            lines.push(format!("*{}*", caption));
+            lines.push(String::new());
+        }
+        lines.push(line(first));
+        lines.push(format!("|{}", " --- |".repeat(first.len())));
+        lines.extend(rest.iter().map(|row| line(row)));
+        lines.join("\n")
+    }
+
+    // A grid drawn with +, - and |, with the header row underlined with =
+    pub fn to_ascii(&self) -> String {
+        self.with_caption(self.ascii_grid(), "\n")
+    }
+
+    // The same grid is an RST grid table, with the caption as a paragraph of its own
+    pub fn to_rst(&self) -> String {
+        self.with_caption(self.ascii_grid(), "\n\n")
+    }
+
+    fn with_caption(&self, grid: String, separator: &str) -> String {
+        match &self.caption {
+            Some(caption) if !grid.is_empty() => format!("{}{}{}", caption, separator, grid),
+            _ => grid,
+        }
+    }
+
+    // Cells spanning several rows or columns are drawn merged, the way RST grid tables allow
+    fn ascii_grid(&self) -> String {
+        let grid = self.grid();
+        let Some(first) = grid.first() else {
+            return String::new();
+        };
+        let (height, width) = (grid.len(), first.len());
+        let text = |slot: &Slot| slot.cell.text.replace('\n', " ");
+        // Where the cell covering each position ends, to the right and downwards
+        let end = |row: usize, column: usize| {
+            let origin = grid[row][column].origin;
+            let right = (column..width)
+                .take_while(|&column| grid[row][column].origin == origin)
+                .last()
+                .unwrap_or(column);
+            let bottom = (row..height)
+                .take_while(|&row| grid[row][column].origin == origin)
+                .last()
+                .unwrap_or(row);
+            (bottom, right)
+        };
+
+        // Columns fit their own cells first, then widen for the cells spanning them
+        let mut widths = vec![0; width];
+        let mut spanning = vec![];
+        for (row_index, row) in grid.iter().enumerate() {
+            for (column, slot) in row.iter().enumerate() {
+                if slot.origin != (row_index, column) {
+                    continue;
+                }
+                let (_, right) = end(row_index, column);
+                let length = text(slot).chars().count();
+                if right == column {
+                    widths[column] = widths[column].max(length);
+                } else {
+                    spanning.push((column, right, length));
+                }
+            }
+        }
+        spanning.sort_by_key(|&(left, right, _)| right - left);
+        for (left, right, length) in spanning {
+            let available: usize = widths[left..=right].iter().sum::<usize>() + 3 * (right - left);
+            if length > available {
+                widths[right] += length - available;
+            }
+        }
+
+        let same =
+            |a: (usize, usize), b: (usize, usize)| grid[a.0][a.1].origin == grid[b.0][b.1].origin;
+        // Whether a line runs between two columns, or above a row, at a position
+        let vertical = |row: usize, column: usize| {
+            row < height
+                && (column == 0 || column == width || !same((row, column - 1), (row, column)))
+        };
+        let horizontal = |row: usize, column: usize| {
+            column < width && (row == 0 || row == height || !same((row - 1, column), (row, column)))
+        };
+        // The header line can only be drawn under a first row nothing spans down from
+        let header_line =
+            self.has_header() && height > 1 && (0..width).all(|column| horizontal(1, column));
+
+        let border = |row: usize| {
+            let fill = if row == 1 && header_line { '=' } else { '-' };
+            // Corners where lines meet, plain lines where they only go through
+            let junction = |column: usize| {
+                let up = row > 0 && vertical(row - 1, column);
+                let down = vertical(row, column);
+                let left = column > 0 && horizontal(row, column - 1);
+                let right = horizontal(row, column);
+                match (up || down, left || right) {
+                    (true, true) => '+',
+                    (true, false) => '|',
+                    (false, true) => fill,
+                    (false, false) => ' ',
+                }
+            };
+            let mut line = String::new();
+            for (column, column_width) in widths.iter().enumerate() {
+                line.push(junction(column));
+                // No line across a cell spanning down
+                let segment = if horizontal(row, column) { fill } else { ' ' };
+                line.extend(std::iter::repeat_n(segment, column_width + 2));
+            }
+            line.push(junction(width));
+            line
+        };
+        let content = |row: usize| {
+            let mut line = String::from("|");
+            let mut column = 0;
+            while column < width {
+                let slot = &grid[row][column];
+                let (_, right) = end(row, column);
+                let span_width: usize =
+                    widths[column..=right].iter().sum::<usize>() + 3 * (right - column);
+                let shown = if slot.origin.0 == row {
+                    text(slot)
+                } else {
+                    String::new()
+                };
+                line.push_str(&format!(
+                    " {}{} |",
+                    shown,
+                    " ".repeat(span_width - shown.chars().count())
+                ));
+                column = right + 1;
+            }
+            line
+        };
+
+        let mut lines = vec![];
+        for row in 0..height {
+            lines.push(border(row));
+            lines.push(content(row));
+        }
+        lines.push(border(height));
+        lines.join("\n")
+    }
+}
+
+// A position in the grid and the cell covering it, cells spanning several rows or columns
+// cover more than one
+#[derive(Clone, Copy)]
+pub struct Slot<'a> {
+    pub cell: &'a Cell,
+    // Row and column of the top left position of the cell, where its text goes
+    pub origin: (usize, usize),
+}
+
+impl Slot<'_> {
+    fn empty(row: usize, column: usize) -> Self {
+        Slot {
+            cell: &EMPTY_CELL,
+            origin: (row, column),
+        }
+    }
+}
+
+static EMPTY_CELL: Cell = Cell {
+    text: String::new(),
+    is_header: false,
+    colspan: 1,
+    rowspan: 1,
+};
+
+// Tables that aren't inside other tables, with where they are in the wikitext
+pub fn tables(wikitext: &str) -> Vec<(Range<usize>, Table)> {
+    let mut found = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut lines: Vec<&str> = vec![];
+    let mut offset = 0;
+
+    for line in wikitext.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        if trimmed.starts_with("{|") {
+            if depth == 0 {
+                start = line_start;
+                lines.clear();
+                depth += 1;
+                continue;
+            }
+            depth += 1;
+        } else if trimmed.starts_with("|}") && depth > 0 {
+            depth -= 1;
+            if depth == 0 {
+                let end = line_start + line.trim_end_matches('\n').len();
+                found.push((start..end, Table::parse(&lines)));
+                continue;
+            }
+        }
+        if depth > 0 {
+            lines.push(line.trim_end_matches('\n'));
+        }
+    }
+    found
+}
+
+pub fn article_tables(article: &RawArticle) -> Vec<Table> {
+    tables(&article.wikitext())
+        .into_iter()
+        .map(|(_, table)| table)
+        .collect()
+}
+
+// Saves every table of the article into a file of its own
+pub fn save_tables(
+    link: &str,
+    options: &DownloadOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let article = raw_article_from_link(link, options.as_of.as_deref())?;
+    let tables = article_tables(&article);
+    if tables.is_empty() {
+        println!("\x1b[31m⚠️ No tables in the article.\x1b[0m");
+        return Ok(());
+    }
+    let stem = file_stem(&article.title);
+    for (index, table) in tables.iter().enumerate() {
+        let file_name = format!("{}.table{}.{}", stem, index + 1, options.format.extension());
+        fs::write(&file_name, table.render(options.format))?;
+        println!("\x1B[32mSaved to {}\x1B[0m", file_name);
+    }
+    Ok(())
+}
+
+pub fn table_marker(index: usize) -> String {
+    format!("{}{}{}", MARKER_START, index, MARKER_END)
+}
+
+// Swaps every table for a marker, so that it can be rendered in its place after parsing
+pub fn extract_tables(wikitext: &str) -> (String, Vec<Table>) {
+    let mut text = wikitext.to_string();
+    let found = tables(wikitext);
+    for (index, (range, _)) in found.iter().enumerate().rev() {
+        text.replace_range(range.clone(), &table_marker(index));
+    }
+    (text, found.into_iter().map(|(_, table)| table).collect())
+}
+
+pub fn restore_tables(text: &str, tables: &[Table], render: impl Fn(&Table) -> String) -> String {
+    let marker = Regex::new(&format!("{}(\\d+){}", MARKER_START, MARKER_END)).unwrap();
+    marker
+        .replace_all(text, |captures: &regex::Captures| {
+            captures[1]
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| tables.get(index))
+                .map_or(String::new(), &render)
+        })
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIKITEXT: &str = r#"Before
+{| class="wikitable"
+|+ Busiest [[railway station|stations]]
+! Station !! Lines
+|-
+| rowspan="2" | [[Howrah Junction railway station|Howrah]] || ''23''
+|-
+| 8<ref>Estimate</ref>
+|-
+| colspan="2" style="text-align:center" | Total, with a {{nowrap|long note}}
+and more
+|}
+After"#;
+
+    #[test]
+    fn parses_spans_and_attributes() {
+        let found = tables(WIKITEXT);
+        assert_eq!(found.len(), 1);
+        let (range, table) = &found[0];
+        assert!(WIKITEXT[range.clone()].starts_with("{|"));
+        assert!(WIKITEXT[range.clone()].ends_with("|}"));
+        assert_eq!(table.caption.as_deref(), Some("Busiest stations"));
+
+        assert_eq!(
+            table.to_csv(),
+            "Station,Lines\nHowrah,23\n,8\n\"Total, with a long note and more\",\n"
+        );
+        assert_eq!(table.to_json()["header"], json!(["Station", "Lines"]));
+    }
+
+    #[test]
+    fn caps_huge_spans() {
+        assert_eq!(span("3", MAX_COLSPAN), 3);
+        assert_eq!(span(" 2px", MAX_COLSPAN), 2);
+        assert_eq!(span("0", MAX_COLSPAN), 1);
+        assert_eq!(span("-4", MAX_COLSPAN), 1);
+        assert_eq!(span("wide", MAX_COLSPAN), 1);
+        assert_eq!(span("5000", MAX_COLSPAN), MAX_COLSPAN);
+        assert_eq!(span("99999999999999999999999", MAX_ROWSPAN), MAX_ROWSPAN);
+
+        let wikitext =
+            "{|\n| colspan=\"99999999999\" rowspan=\"99999999999\" | Wide\n|-\n| Narrow\n|}";
+        let table = &tables(wikitext)[0].1;
+        assert_eq!(table.rows[0][0].colspan, MAX_COLSPAN);
+        assert_eq!(table.rows[0][0].rowspan, MAX_ROWSPAN);
+        let grid = table.grid_text();
+        // The rowspan stops at the last row, the next cell goes after the colspan
+        assert_eq!(grid.len(), 2);
+        assert_eq!(grid[0].len(), MAX_COLSPAN + 1);
+        assert_eq!(grid[1][MAX_COLSPAN], "Narrow");
+    }
+
+    #[test]
+    fn renders_grids() {
+        let (text, tables) = extract_tables(WIKITEXT);
+        assert!(text.starts_with("Before\n\u{E002}0\u{E003}\nAfter"));
+        let table = &tables[0];
+        assert_eq!(
+            table.to_ascii(),
+            "Busiest stations
++---------+------------------------+
+| Station | Lines                  |
++=========+========================+
+| Howrah  | 23                     |
+|         +------------------------+
+|         | 8                      |
++---------+------------------------+
+| Total, with a long note and more |
++----------------------------------+"
+        );
+        assert!(table.to_markdown().starts_with(
+            "*Busiest stations*\n\n| Station | Lines |\n| --- | --- |\n| Howrah | 23 |\n|  | 8 |"
+        ));
+    }
+}
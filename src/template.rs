@@ -0,0 +1,504 @@
+use std::ops::Range;
+use std::sync::{LazyLock, OnceLock};
+
+use regex::Regex;
+
+use crate::api::api_request;
+
+// A {{template}} call, unnamed parameters are numbered from 1 like in MediaWiki
+pub struct Template {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl Template {
+    // `inner` is everything between the braces
+    fn parse(inner: &str) -> Self {
+        let mut parts = split_top_level(inner, "|").into_iter();
+        let name = parts.next().unwrap_or_default();
+        let name = name
+            .trim()
+            .trim_start_matches("Template:")
+            .replace('_', " ");
+        let mut position = 0;
+        let params = parts
+            .map(|part| match split_top_level(part, "=").as_slice() {
+                [key, ..] if part.len() > key.len() => (
+                    key.trim().to_string(),
+                    part[key.len() + 1..].trim().to_string(),
+                ),
+                _ => {
+                    position += 1;
+                    (position.to_string(), part.trim().to_string())
+                }
+            })
+            .collect();
+        Template {
+            name: name.split_whitespace().collect::<Vec<_>>().join(" "),
+            params,
+        }
+    }
+
+    pub fn param(&self, key: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Like MediaWiki, only the first letter of the name is case insensitive
+    pub fn name_starts_with(&self, prefix: &str) -> bool {
+        let mut name = self.name.chars();
+        let mut prefix = prefix.chars();
+        match (name.next(), prefix.next()) {
+            (Some(first), Some(prefix_first)) => {
+                first.to_lowercase().eq(prefix_first.to_lowercase())
+                    && name.as_str().starts_with(prefix.as_str())
+            }
+            (_, None) => true,
+            (None, Some(_)) => false,
+        }
+    }
+}
+
+// Templates that aren't inside other templates, with where they are in the wikitext
+pub fn templates(wikitext: &str) -> Vec<(Range<usize>, Template)> {
+    let mut found = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut chars = wikitext.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        if c == '{' && next == Some('{') {
+            chars.next();
+            if depth == 0 {
+                start = index;
+            }
+            depth += 1;
+        } else if c == '}' && next == Some('}') && depth > 0 {
+            chars.next();
+            depth -= 1;
+            if depth == 0 {
+                let end = index + 2;
+                found.push((start..end, Template::parse(&wikitext[start + 2..index])));
+            }
+        }
+    }
+    found
+}
+
+// Splits on `separator` wherever it isn't inside a template or a link
+pub fn split_top_level<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = vec![];
+    let mut braces = 0;
+    let mut brackets = 0;
+    let mut part_start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        if braces == 0 && brackets == 0 && text[index..].starts_with(separator) {
+            parts.push(&text[part_start..index]);
+            part_start = index + separator.len();
+            // Skip over the rest of the separator
+            while chars.next_if(|&(next, _)| next < part_start).is_some() {}
+            continue;
+        }
+        let next = chars.peek().map(|&(_, next)| next);
+        match c {
+            '{' if next == Some('{') => {
+                chars.next();
+                braces += 1;
+            }
+            '}' if next == Some('}') && braces > 0 => {
+                chars.next();
+                braces -= 1;
+            }
+            '[' if next == Some('[') => {
+                chars.next();
+                brackets += 1;
+            }
+            ']' if next == Some(']') && brackets > 0 => {
+                chars.next();
+                brackets -= 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[part_start..]);
+    parts
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// {{birth date|1861|5|7}} and friends, the unnamed parameters are year, month and day
+fn date_from_params(template: &Template) -> String {
+    let part = |offset: usize| {
+        template
+            .param(&(offset + 1).to_string())
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+    };
+    match (part(0), part(1), part(2)) {
+        (Some(year), Some(month), Some(day)) => format!("{:0>4}-{:0>2}-{:0>2}", year, month, day),
+        (Some(year), Some(month), None) => {
+            let month = month
+                .parse::<usize>()
+                .ok()
+                .and_then(|number| MONTHS.get(number.checked_sub(1)?).copied())
+                .unwrap_or(month);
+            format!("{} {}", month, year)
+        }
+        (Some(year), None, None) => year.to_string(),
+        _ => String::new(),
+    }
+}
+
+// Units {{convert}} spells out rather than abbreviates, those go plural like "25 acres"
+const SPELLED_UNITS: [&str; 4] = ["acre", "ton", "knot", "hand"];
+
+fn unit_name(value: &str, unit: &str) -> String {
+    if SPELLED_UNITS.contains(&unit) && value != "1" {
+        format!("{}s", unit)
+    } else {
+        unit.to_string()
+    }
+}
+
+// What the templates common in infoboxes, citations and running text display, None for the ones we don't know
+fn expand(template: &Template) -> Option<String> {
+    let first = || inline_plaintext(template.param("1").unwrap_or_default());
+    let positional = || {
+        template
+            .params
+            .iter()
+            .filter(|(key, _)| key.parse::<usize>().is_ok())
+            .map(|(_, value)| inline_plaintext(value))
+            .filter(|value| !value.is_empty())
+            .collect::<Vec<_>>()
+    };
+    let name = template.name.to_lowercase();
+    let expanded =
+        match name.as_str() {
+            "birth date" | "birth date and age" | "death date" | "death date and age"
+            | "start date" | "start date and age" | "end date" | "dob" | "film date" => {
+                date_from_params(template)
+            }
+            "birth year and age" | "death year and age" => first(),
+            "plainlist" | "flatlist" | "plain list" | "flat list" => {
+                let items: Vec<String> = template
+                    .param("1")
+                    .unwrap_or_default()
+                    .lines()
+                    .map(|line| inline_plaintext(line.trim_start_matches(['*', ' '])))
+                    .filter(|item| !item.is_empty())
+                    .collect();
+                items.join(", ")
+            }
+            "ubl" | "unbulleted list" | "hlist" | "bulleted list" | "ublist"
+            | "collapsible list" => positional().join(", "),
+            "nowrap" | "nobold" | "noitalic" | "small" | "big" | "smaller" | "larger" | "abbr"
+            | "url" | "native name" | "sic" | "nobr" | "ipa" | "script" | "tooltip"
+            | "keypress" => first(),
+            "lang" | "transl" | "transliteration" => {
+                inline_plaintext(template.param("2").unwrap_or_default())
+            }
+            "convert" | "cvt" => {
+                let values = positional();
+                match values.as_slice() {
+                    // {{convert|5|-|7|km}}
+                    [from, range, to, unit, ..]
+                        if ["-", "–", "to", "and", "or"].contains(&range.as_str()) =>
+                    {
+                        let range = if range == "-" { "–" } else { range };
+                        let spacing = if range == "–" { "" } else { " " };
+                        let unit = unit_name(to, unit);
+                        format!("{from}{spacing}{range}{spacing}{to} {unit}")
+                    }
+                    [value, unit, ..] => format!("{} {}", value, unit_name(value, unit)),
+                    _ => values.into_iter().take(1).collect(),
+                }
+            }
+            "marriage" => match (template.param("1"), template.param("2")) {
+                (Some(spouse), Some(year)) => format!(
+                    "{} (m. {})",
+                    inline_plaintext(spouse),
+                    inline_plaintext(year)
+                ),
+                _ => first(),
+            },
+            "circa" | "c." => format!("c. {}", first()),
+            "as of" => {
+                let as_of = if template.param("lc") == Some("y") {
+                    "as of"
+                } else {
+                    "As of"
+                };
+                format!("{} {}", as_of, date_from_params(template))
+            }
+            "frac" | "sfrac" => positional().join("/"),
+            "nombre" | "unité" => positional().join(" "),
+            "ndash" => "–".to_string(),
+            "snd" | "spaced ndash" => " – ".to_string(),
+            "mdash" | "spaced mdash" => "—".to_string(),
+            "nbsp" | "sp" => " ".to_string(),
+            // Footnotes and maintenance tags, nothing to show in the text
+            "sfn" | "sfnp" | "efn" | "refn" | "r" | "rp" | "harvnb" | "coord"
+            | "citation needed" | "cn" | "clarify" | "when" | "dubious" | "anchor" | "-"
+            | "clear" | "toc" | "reflist" => String::new(),
+            // {{IPA-bn|...}} and {{lang-fr|...}}
+            _ if name.starts_with("ipa-") || name.starts_with("lang-") => first(),
+            _ => return None,
+        };
+    Some(expanded)
+}
+
+static REMOTE_EXPANSION: OnceLock<bool> = OnceLock::new();
+
+// Must be called before the first article is rendered, otherwise templates are only expanded locally
+pub fn configure(expand_remotely: bool) {
+    if REMOTE_EXPANSION.set(expand_remotely).is_err() {
+        panic!("Template expansion is already configured.");
+    }
+}
+
+pub fn expands_remotely() -> bool {
+    REMOTE_EXPANSION.get().copied().unwrap_or_default()
+}
+
+// Whether each template has text around it on its line, i.e. leaves a hole in a sentence when dropped
+fn inline_templates(wikitext: &str, found: &[(Range<usize>, Template)]) -> Vec<bool> {
+    // Other templates on the same line don't count as text
+    let mut blanked = wikitext.as_bytes().to_vec();
+    for (range, _) in found {
+        for byte in &mut blanked[range.clone()] {
+            if *byte != b'\n' {
+                *byte = b' ';
+            }
+        }
+    }
+    found
+        .iter()
+        .map(|(range, _)| {
+            let line_start = blanked[..range.start]
+                .iter()
+                .rposition(|&byte| byte == b'\n')
+                .map_or(0, |index| index + 1);
+            let line_end = blanked[range.end..]
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(blanked.len(), |index| range.end + index);
+            blanked[line_start..range.start]
+                .iter()
+                .chain(&blanked[range.end..line_end])
+                .any(|byte| !byte.is_ascii_whitespace())
+        })
+        .collect()
+}
+
+// Separates the templates sent to the wiki in one go, and survives their expansion as is
+const EXPANSION_SEPARATOR: &str = "\n\u{E004}\n";
+// Keeps the requests to the API reasonably short
+const MAX_EXPANSION_BATCH: usize = 2000;
+
+// Plain text of what the wiki renders for each of the templates
+fn expand_remotely(host: &str, title: &str, templates: &[&str]) -> Result<Vec<String>, String> {
+    let mut batches: Vec<Vec<&str>> = vec![];
+    let mut batch_length = 0;
+    for &template in templates {
+        match batches.last_mut() {
+            Some(batch) if batch_length + template.len() <= MAX_EXPANSION_BATCH => {
+                batch.push(template);
+                batch_length += template.len();
+            }
+            _ => {
+                batches.push(vec![template]);
+                batch_length = template.len();
+            }
+        }
+    }
+
+    let mut expanded = vec![];
+    for batch in batches {
+        let response = api_request(
+            host,
+            "expandtemplates",
+            &[
+                ("prop", "wikitext"),
+                ("title", title),
+                ("text", &batch.join(EXPANSION_SEPARATOR)),
+            ],
+        )?;
+        let wikitext = response["expandtemplates"]["wikitext"]
+            .as_str()
+            .ok_or("Error: No expanded wikitext in the response")?;
+        let parts: Vec<&str> = wikitext.split(EXPANSION_SEPARATOR).collect();
+        if parts.len() != batch.len() {
+            return Err(
+                "Error: The expanded templates don't line up with the ones sent".to_string(),
+            );
+        }
+        expanded.extend(parts.into_iter().map(inline_plaintext));
+    }
+    Ok(expanded)
+}
+
+// Swaps the templates for what they display. The ones we don't know are dropped, unless remote
+// expansion is on, then the wiki expands the ones in running text
+pub fn expand_templates(wikitext: &str, host: &str, title: &str) -> String {
+    let found = templates(wikitext);
+    let mut expansions: Vec<Option<String>> =
+        found.iter().map(|(_, template)| expand(template)).collect();
+
+    if expands_remotely() {
+        let inline = inline_templates(wikitext, &found);
+        let missing: Vec<usize> = (0..found.len())
+            .filter(|&index| expansions[index].is_none() && inline[index])
+            .collect();
+        let texts: Vec<&str> = missing
+            .iter()
+            .map(|&index| &wikitext[found[index].0.clone()])
+            .collect();
+        if !texts.is_empty() {
+            match expand_remotely(host, title, &texts) {
+                Ok(expanded) => {
+                    for (index, text) in missing.into_iter().zip(expanded) {
+                        expansions[index] = Some(text);
+                    }
+                }
+                Err(err) => println!(
+                    "\x1b[31m⚠️ Could not expand the templates of {}: {}\x1b[0m",
+                    title, err
+                ),
+            }
+        }
+    }
+
+    let mut text = wikitext.to_string();
+    for ((range, _), expansion) in found.into_iter().zip(expansions).rev() {
+        text.replace_range(range, &expansion.unwrap_or_default());
+    }
+    text
+}
+
+// Compiled once, inline_plaintext runs for every infobox field and reference
+static COMMENTS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<!--.*?-->").unwrap());
+static REFS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<ref[^>]*/>|<ref[^>]*>.*?</ref>").unwrap());
+static LINE_BREAKS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<br\s*/?>").unwrap());
+static FILES: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\[\[(File|Image):[^\[\]]*(\[\[[^\[\]]*\]\][^\[\]]*)*\]\]").unwrap()
+});
+static PIPED_LINKS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[\[[^\[\]|]*\|([^\[\]]*)\]\]").unwrap());
+static LINKS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[\[([^\[\]|]*)\]\]").unwrap());
+static LABELLED_EXTERNAL_LINKS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[(?:https?:)?//[^\s\]]+\s+([^\]]*)\]").unwrap());
+static EXTERNAL_LINKS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[(?:https?:)?//[^\s\]]+\]").unwrap());
+static TAGS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]+>").unwrap());
+
+// Plain text of a short bit of wikitext, such as the value of an infobox field
+pub fn inline_plaintext(wikitext: &str) -> String {
+    let text = COMMENTS.replace_all(wikitext, "");
+    let text = REFS.replace_all(&text, "");
+    let mut text = LINE_BREAKS.replace_all(&text, ", ").to_string();
+
+    // Going backwards keeps the earlier ranges valid, nested templates are expanded by their parents
+    for (range, template) in templates(&text).into_iter().rev() {
+        text.replace_range(range, &expand(&template).unwrap_or_default());
+    }
+
+    let text = FILES.replace_all(&text, "");
+    let text = PIPED_LINKS.replace_all(&text, "$1");
+    let text = LINKS.replace_all(&text, "$1");
+    let text = LABELLED_EXTERNAL_LINKS.replace_all(&text, "$1");
+    let text = EXTERNAL_LINKS.replace_all(&text, "");
+    let text = TAGS.replace_all(&text, "");
+    let text = text
+        .replace("'''", "")
+        .replace("''", "")
+        .replace("&nbsp;", " ")
+        .replace("&ndash;", "–")
+        .replace("&mdash;", "—")
+        .replace("&amp;", "&");
+
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    text.trim_matches([',', ' '])
+        .replace(" ,", ",")
+        .replace(", ,", ",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::wiki_host;
+
+    #[test]
+    fn parses_nested_templates() {
+        let wikitext = "a {{Infobox person | name = [[Rabindranath Tagore|Tagore]] | birth_date = {{birth date|1861|5|7}} | x }} b {{reflist}}";
+        let found = templates(wikitext);
+        assert_eq!(found.len(), 2);
+        let (range, infobox) = &found[0];
+        assert_eq!(&wikitext[range.clone()][..11], "{{Infobox p");
+        assert_eq!(infobox.name, "Infobox person");
+        assert_eq!(
+            infobox.param("name"),
+            Some("[[Rabindranath Tagore|Tagore]]")
+        );
+        assert_eq!(infobox.param("birth_date"), Some("{{birth date|1861|5|7}}"));
+        assert_eq!(infobox.param("1"), Some("x"));
+    }
+
+    #[test]
+    fn finds_templates_in_running_text() {
+        let wikitext = "{{Short description|City}}{{Use dmy dates}}\nIt lies {{convert|5|km}} away.\n{{Infobox settlement\n| name = Kolkata\n}}";
+        assert_eq!(
+            inline_templates(wikitext, &templates(wikitext)),
+            [false, false, true, false]
+        );
+        assert_eq!(
+            expand_templates(wikitext, "en.wikipedia.org", "Kolkata"),
+            "\nIt lies 5 km away.\n"
+        );
+    }
+
+    #[test]
+    fn expands_templates_through_the_api() {
+        let expanded = expand_remotely(
+            wiki_host(),
+            "Kolkata",
+            &["{{Height|m=1.8}}", "{{Sanskrit|''Kalikata''}}"],
+        )
+        .unwrap();
+        assert_eq!(expanded, ["1.8 m (5 ft 11 in)", "Sanskrit: Kalikata"]);
+    }
+
+    #[test]
+    fn renders_values_to_plaintext() {
+        assert_eq!(inline_plaintext("{{birth date|1861|5|7}}"), "1861-05-07");
+        assert_eq!(
+            inline_plaintext("[[British Raj|British India]]<ref>Cited</ref>"),
+            "British India"
+        );
+        assert_eq!(
+            inline_plaintext("{{plainlist|\n* [[Poet]]\n* ''Writer''\n}}"),
+            "Poet, Writer"
+        );
+        assert_eq!(inline_plaintext("Kolkata<br />Bengal"), "Kolkata, Bengal");
+        assert_eq!(inline_plaintext("{{coord|22|34|N}}"), "");
+        assert_eq!(inline_plaintext("{{convert|5|-|7|km}}"), "5–7 km");
+        assert_eq!(inline_plaintext("{{lang|fr|''la ville''}}"), "la ville");
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::thread;
+
+use crate::cache::{self, CacheConfig};
+use crate::fixtures::Fixtures;
+use crate::http::{self, HttpClient, HttpConfig};
+use crate::site::{self, SiteProfile};
+
+// Host (with port) of a tiny wiki stand-in serving the responses under tests/fixtures
+pub fn wiki_host() -> &'static str {
+    static HOST: OnceLock<String> = OnceLock::new();
+    HOST.get_or_init(|| {
+        let fixtures =
+            Fixtures::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures"))
+                .expect("Failed to load the fixtures.");
+        let listener =
+            TcpListener::bind("127.0.0.1:0").expect("Failed to start the stand-in wiki.");
+        let host = listener.local_addr().unwrap().to_string();
+
+        let origin = format!("http://{}", host);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                serve(stream, &origin, &fixtures);
+            }
+        });
+
+        // Everything process-wide is configured once, the tests share it
+        site::configure(vec![SiteProfile {
+            scheme: "http".to_string(),
+            // The stand-in answers the Wikidata requests too
+            wikidata_host: Some(host.clone()),
+            ..SiteProfile::default_for(&host)
+        }]);
+        http::set_client(Box::new(HttpClient::new(HttpConfig {
+            requests_per_second: 0.0,
+            ..Default::default()
+        })));
+        cache::configure(CacheConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        host
+    })
+}
+
+pub fn wiki_link(title: &str) -> String {
+    format!("http://{}/wiki/{}", wiki_host(), title)
+}
+
+fn serve(stream: TcpStream, origin: &str, fixtures: &Fixtures) {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    // Headers don't matter, but have to be read before answering
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|read| read > 2) {
+        header.clear();
+    }
+
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, body) = match fixtures.find(&format!("{}{}", origin, target), false) {
+        // Responses link back to the stand-in wiki through $ORIGIN
+        Some(body) => ("200 OK", body.replace("$ORIGIN", origin)),
+        None => ("404 Not Found", format!("No fixture for {}", target)),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = (&stream).write_all(response.as_bytes());
+}
@@ -0,0 +1,514 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde_json::Value;
+
+use crate::api::{article_link, normalize_title, query};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::references::{article_references, citations, CitationFormat};
+use crate::site::profile;
+use crate::{
+    file_stem, raw_articles_from_links, render, source_json, DownloadOptions, OutputFormat,
+    RawArticle, SOURCE_EXTENSION, TITLES_PER_REQUEST,
+};
+
+// Edits, new pages, deletions and moves in the main namespace since `since`, oldest first, a
+// batch at a time. Returns the server time of the first request
+fn recent_changes(
+    host: &str,
+    since: &str,
+    mut on_batch: impl FnMut(&[Value]),
+) -> Result<String, String> {
+    let mut curtimestamp = String::new();
+    let mut rccontinue: Option<String> = None;
+    loop {
+        let mut params = vec![
+            ("list", "recentchanges"),
+            ("rcnamespace", "0"),
+            ("rctype", "edit|new|log"),
+            ("rcprop", "title|timestamp|loginfo"),
+            ("rcdir", "newer"),
+            ("rcstart", since),
+            ("rclimit", "max"),
+            ("curtimestamp", "1"),
+        ];
+        if let Some(token) = &rccontinue {
+            params.push(("rccontinue", token));
+        }
+        let response = query(host, &params)?;
+        if curtimestamp.is_empty() {
+            curtimestamp = response["curtimestamp"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+        }
+        if let Some(batch) = response["query"]["recentchanges"].as_array() {
+            on_batch(batch);
+        }
+        match response["continue"]["rccontinue"].as_str() {
+            Some(token) => rccontinue = Some(token.to_string()),
+            None => break,
+        }
+    }
+    Ok(curtimestamp)
+}
+
+// Titles of the pages moved into the main namespace from another one (a draft being published)
+// since `since`, oldest first. Moves are logged under the namespace they come from, so these
+// never show up in the recent changes of the main namespace
+fn moves_into_main(host: &str, since: &str, mut on_move: impl FnMut(String)) -> Result<(), String> {
+    let mut lecontinue: Option<String> = None;
+    loop {
+        let mut params = vec![
+            ("list", "logevents"),
+            ("letype", "move"),
+            ("leprop", "title|details"),
+            ("ledir", "newer"),
+            ("lestart", since),
+            ("lelimit", "max"),
+        ];
+        if let Some(token) = &lecontinue {
+            params.push(("lecontinue", token));
+        }
+        let response = query(host, &params)?;
+        for event in response["query"]["logevents"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            let target = &event["params"];
+            if event["ns"].as_i64() != Some(0) && target["target_ns"].as_i64() == Some(0) {
+                if let Some(title) = target["target_title"].as_str() {
+                    on_move(normalize_title(title));
+                }
+            }
+        }
+        match response["continue"]["lecontinue"].as_str() {
+            Some(token) => lecontinue = Some(token.to_string()),
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+// What the changes on one wiki mean for the corpus. Only the articles they touch are kept, the
+// changes themselves are gone once applied
+struct CorpusChanges {
+    // Articles of the corpus by normalized title, along with the name of their files if they
+    // were downloaded already
+    corpus: HashMap<String, Option<String>>,
+    include_new: bool,
+    to_fetch: BTreeSet<String>,
+    to_delete: BTreeSet<String>,
+    moved_count: usize,
+    deleted_count: usize,
+}
+
+impl CorpusChanges {
+    fn add_new(&mut self, title: String) {
+        if self.include_new || self.corpus.contains_key(&title) {
+            self.corpus.entry(title.clone()).or_default();
+            self.to_fetch.insert(title);
+        }
+    }
+
+    fn remove(&mut self, title: &str) -> bool {
+        let Some(stem) = self.corpus.remove(title) else {
+            return false;
+        };
+        self.to_fetch.remove(title);
+        self.to_delete.extend(stem);
+        true
+    }
+
+    fn delete(&mut self, title: &str) {
+        if self.remove(title) {
+            self.deleted_count += 1;
+        }
+    }
+
+    fn apply(&mut self, change: &Value) {
+        let Some(title) = change["title"].as_str() else {
+            return;
+        };
+        let title = normalize_title(title);
+        let kind = (
+            change["type"].as_str().unwrap_or_default(),
+            change["logtype"].as_str().unwrap_or_default(),
+            change["logaction"].as_str().unwrap_or_default(),
+        );
+        match kind {
+            ("edit", _, _) if self.corpus.contains_key(&title) => {
+                self.to_fetch.insert(title);
+            }
+            ("new", _, _) | ("log", "delete", "restore") => self.add_new(title),
+            ("log", "delete", "delete") => self.delete(&title),
+            ("log", "move", _) => {
+                if !self.remove(&title) {
+                    return;
+                }
+                // Moving out of the main namespace is as good as a deletion for us
+                let params = &change["logparams"];
+                match (
+                    params["target_ns"].as_i64(),
+                    params["target_title"].as_str(),
+                ) {
+                    (Some(0), Some(target)) => {
+                        let target = normalize_title(target);
+                        self.corpus.entry(target.clone()).or_default();
+                        self.to_fetch.insert(target);
+                        self.moved_count += 1;
+                    }
+                    _ => self.deleted_count += 1,
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Timestamp of the oldest change the wiki still remembers. Wikis only keep recent changes for
+// a while ($wgRCMaxAge, 30 days on Wikimedia), anything older is gone from the list
+fn oldest_recent_change(host: &str) -> Result<Option<String>, String> {
+    let response = query(
+        host,
+        &[
+            ("list", "recentchanges"),
+            ("rcprop", "timestamp"),
+            ("rcdir", "newer"),
+            ("rclimit", "1"),
+        ],
+    )?;
+    Ok(response["query"]["recentchanges"][0]["timestamp"]
+        .as_str()
+        .map(|timestamp| timestamp.to_string()))
+}
+
+// Compares the latest revision of every article with the one in the manifest, for when the
+// recent changes don't go back far enough. Returns the titles that changed, the ones that no
+// longer exist and the server time of the first request
+fn stale_articles(
+    host: &str,
+    revids: &BTreeMap<String, u64>,
+) -> Result<(Vec<String>, Vec<String>, String), String> {
+    let titles: Vec<&String> = revids.keys().collect();
+    let mut changed = vec![];
+    let mut missing = vec![];
+    let mut curtimestamp = String::new();
+    for chunk in titles.chunks(TITLES_PER_REQUEST) {
+        let joined_titles = chunk
+            .iter()
+            .map(|title| title.as_str())
+            .collect::<Vec<_>>()
+            .join("|");
+        let response = query(
+            host,
+            &[
+                ("prop", "revisions"),
+                ("titles", &joined_titles),
+                ("rvprop", "ids"),
+                ("curtimestamp", "1"),
+            ],
+        )?;
+        if curtimestamp.is_empty() {
+            curtimestamp = response["curtimestamp"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+        }
+        for page in response["query"]["pages"].as_array().into_iter().flatten() {
+            let Some(title) = page["title"].as_str() else {
+                continue;
+            };
+            let title = normalize_title(title);
+            if page["missing"].as_bool() == Some(true) {
+                missing.push(title);
+            } else if page["revisions"][0]["revid"].as_u64() != revids.get(&title).copied() {
+                changed.push(title);
+            }
+        }
+    }
+    Ok((changed, missing, curtimestamp))
+}
+
+// Brings a download directory up to date with the wiki, using the sync time in its manifest
+pub fn update_corpus(
+    dir: &Path,
+    include_new: bool,
+    options: &DownloadOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut manifest = Manifest::load(dir)?;
+    let last_sync = manifest
+        .last_sync
+        .clone()
+        .ok_or("Manifest has no sync time, was this directory downloaded with wp?")?;
+
+    let hosts: BTreeSet<String> = manifest
+        .articles
+        .values()
+        .map(|entry| entry.host.clone())
+        .collect();
+    let mut synced_at: Option<String> = None;
+
+    for host in hosts {
+        // Changes older than the wiki keeps would go unnoticed, so check every article instead
+        let oldest_change = oldest_recent_change(&host)?;
+        let is_retained = oldest_change
+            .as_deref()
+            .is_none_or(|oldest| oldest <= last_sync.as_str());
+        let mut changes = CorpusChanges {
+            corpus: manifest
+                .articles
+                .iter()
+                .filter(|(_, entry)| entry.host == host)
+                .map(|(stem, entry)| (normalize_title(&entry.title), Some(stem.clone())))
+                .collect(),
+            include_new,
+            to_fetch: BTreeSet::new(),
+            to_delete: BTreeSet::new(),
+            moved_count: 0,
+            deleted_count: 0,
+        };
+
+        let curtimestamp = if is_retained {
+            println!("⚡ Checking changes on {} since {}...", host, last_sync);
+            // Drafts published since then, before the changes that could have happened to them
+            if include_new {
+                moves_into_main(&host, &last_sync, |title| changes.add_new(title))?;
+            }
+            recent_changes(&host, &last_sync, |batch| {
+                for change in batch {
+                    changes.apply(change);
+                }
+            })?
+        } else {
+            println!(
+                "\x1b[31m⚠️ {} only keeps changes since {}, comparing the revision of every article instead\x1b[0m",
+                host,
+                oldest_change.unwrap_or_default()
+            );
+            if include_new {
+                println!("\x1b[31m⚠️ New articles can't be found this way and are left out\x1b[0m");
+            }
+            let revids: BTreeMap<String, u64> = manifest
+                .articles
+                .values()
+                .filter(|entry| entry.host == host)
+                .map(|entry| (normalize_title(&entry.title), entry.revid))
+                .collect();
+            let (changed, missing, curtimestamp) = stale_articles(&host, &revids)?;
+            changes.to_fetch.extend(changed);
+            for title in missing {
+                changes.delete(&title);
+            }
+            curtimestamp
+        };
+        let CorpusChanges {
+            corpus,
+            to_fetch,
+            to_delete,
+            moved_count,
+            deleted_count,
+            ..
+        } = changes;
+
+        for stem in &to_delete {
+            remove_outputs(dir, stem)?;
+            manifest.articles.remove(stem);
+        }
+
+        // Existing articles keep the title (and so the file name) they were downloaded with
+        let links: Vec<String> = to_fetch
+            .iter()
+            .map(|title| {
+                let existing = corpus
+                    .get(title)
+                    .and_then(|stem| manifest.articles.get(stem.as_ref()?));
+                match existing {
+                    Some(entry) => profile(&host).article_url_encoded(&entry.title),
+                    None => article_link(&host, title),
+                }
+            })
+            .collect();
+        let mut fetched_count = 0;
+        for chunk in links.chunks(TITLES_PER_REQUEST) {
+            for article in raw_articles_from_links(chunk, None).into_iter().flatten() {
+                let stem = file_stem(&article.title);
+                save_outputs(dir, &stem, &article, options)?;
+                manifest
+                    .articles
+                    .insert(stem, ManifestEntry::from_article(&article));
+                fetched_count += 1;
+            }
+        }
+
+        println!(
+            "✅ {} refetched, {} moved, {} deleted",
+            fetched_count, moved_count, deleted_count
+        );
+        // With several wikis, the corpus is only as current as the least recent of them
+        synced_at = match synced_at {
+            Some(timestamp) if timestamp <= curtimestamp => Some(timestamp),
+            _ => Some(curtimestamp),
+        };
+    }
+
+    manifest.as_of = None;
+    manifest.last_sync = synced_at.or(manifest.last_sync);
+    manifest.save(dir)?;
+    Ok(())
+}
+
+fn output_paths(dir: &Path, stem: &str) -> BTreeMap<String, PathBuf> {
+    OutputFormat::value_variants()
+        .iter()
+        .map(OutputFormat::extension)
+        .chain([
+            SOURCE_EXTENSION,
+            CitationFormat::Bibtex.extension(),
+            CitationFormat::CslJson.extension(),
+        ])
+        .map(|extension| {
+            (
+                extension.to_string(),
+                dir.join(format!("{}.{}", stem, extension)),
+            )
+        })
+        .collect()
+}
+
+fn remove_outputs(dir: &Path, stem: &str) -> std::io::Result<()> {
+    for path in output_paths(dir, stem).into_values() {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+// Rewrites the files the article already had, or the ones asked for if it is new to the corpus
+fn save_outputs(
+    dir: &Path,
+    stem: &str,
+    article: &RawArticle,
+    options: &DownloadOptions,
+) -> std::io::Result<()> {
+    let paths = output_paths(dir, stem);
+    let is_new = paths.values().all(|path| !path.exists());
+    for &format in OutputFormat::value_variants() {
+        let path = &paths[format.extension()];
+        let is_wanted = options.format.extension() == format.extension();
+        if path.exists() || (is_new && is_wanted) {
+            let options = DownloadOptions {
+                format,
+                ..options.clone()
+            };
+            fs::write(path, render(article, &options))?;
+        }
+    }
+    for format in [CitationFormat::Bibtex, CitationFormat::CslJson] {
+        let path = &paths[format.extension()];
+        let is_wanted = options
+            .citations
+            .is_some_and(|wanted| wanted.extension() == format.extension());
+        if path.exists() || (is_new && is_wanted) {
+            fs::write(path, citations(&article_references(article), format))?;
+        }
+    }
+    let source_path = &paths[SOURCE_EXTENSION];
+    if source_path.exists() || (is_new && options.keep_source) {
+        fs::write(source_path, source_json(article))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::wiki_host;
+
+    #[test]
+    fn compares_revisions_when_the_changes_are_too_old() {
+        let dir = std::env::temp_dir().join(format!("wp-update-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut manifest = Manifest {
+            // Long before the oldest change the stand-in wiki remembers
+            last_sync: Some("2023-01-01T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+        for (title, revid) in [("Kolkata", 1200000000), ("Atlantis", 5)] {
+            let stem = file_stem(title);
+            fs::write(dir.join(format!("{}.txt", stem)), "Outdated").unwrap();
+            manifest.articles.insert(
+                stem,
+                ManifestEntry {
+                    title: title.to_string(),
+                    host: wiki_host().to_string(),
+                    revid,
+                    timestamp: "2023-01-01T00:00:00Z".to_string(),
+                },
+            );
+        }
+        manifest.save(&dir).unwrap();
+
+        update_corpus(&dir, false, &DownloadOptions::default()).unwrap();
+        let manifest = Manifest::load(&dir).unwrap();
+        let kolkata = fs::read_to_string(dir.join(format!("{}.txt", file_stem("Kolkata"))));
+        let atlantis_exists = dir.join(format!("{}.txt", file_stem("Atlantis"))).exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(kolkata.unwrap().starts_with("Kolkata"));
+        assert!(!atlantis_exists);
+        assert_eq!(manifest.articles.len(), 1);
+        assert_eq!(manifest.articles[&file_stem("Kolkata")].revid, 1221000001);
+        assert_eq!(manifest.last_sync.as_deref(), Some("2024-04-20T12:00:00Z"));
+    }
+
+    #[test]
+    fn applies_the_recent_changes_batch_by_batch() {
+        let dir = std::env::temp_dir().join(format!("wp-update-recent-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut manifest = Manifest {
+            // After the oldest change the stand-in wiki remembers
+            last_sync: Some("2024-04-01T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+        for (title, revid) in [("Kolkata", 1200000000), ("Atlantis", 5)] {
+            let stem = file_stem(title);
+            fs::write(dir.join(format!("{}.txt", stem)), "Outdated").unwrap();
+            manifest.articles.insert(
+                stem,
+                ManifestEntry {
+                    title: title.to_string(),
+                    host: wiki_host().to_string(),
+                    revid,
+                    timestamp: "2024-01-01T00:00:00Z".to_string(),
+                },
+            );
+        }
+        manifest.save(&dir).unwrap();
+
+        update_corpus(&dir, true, &DownloadOptions::default()).unwrap();
+        let manifest = Manifest::load(&dir).unwrap();
+        let kolkata = fs::read_to_string(dir.join(format!("{}.txt", file_stem("Kolkata"))));
+        let kidderpore = fs::read_to_string(dir.join(format!("{}.txt", file_stem("Kidderpore"))));
+        let atlantis_exists = dir.join(format!("{}.txt", file_stem("Atlantis"))).exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Kolkata was edited in the first batch and Atlantis deleted in the second, while
+        // Kidderpore came out of the draft namespace
+        assert!(kolkata.unwrap().starts_with("Kolkata"));
+        assert!(kidderpore.unwrap().starts_with("Kidderpore"));
+        assert!(!atlantis_exists);
+        let mut titles: Vec<_> = manifest
+            .articles
+            .values()
+            .map(|entry| &entry.title)
+            .collect();
+        titles.sort();
+        assert_eq!(titles, ["Kidderpore", "Kolkata"]);
+        assert_eq!(manifest.last_sync.as_deref(), Some("2024-04-20T12:00:00Z"));
+    }
+}
@@ -1,35 +1,47 @@
 use std::{
     fs::File,
-    hash::{DefaultHasher, Hash, Hasher},
     io::{self, BufRead, Write},
     path::Path,
     sync::{Arc, Mutex},
     thread::spawn,
 };
 
-use regex::Regex;
+use regex::{Captures, Regex};
 
-use crate::{plaintext_from_link, FormatType, Token};
+use serde_json::json;
+use url::Url;
 
-pub fn advance(text: &Vec<char>, current: &mut usize, iter: &mut i32) -> char {
-    let max = 150000;
-    if *iter < max {
-        *iter += 1;
-        *current += 1;
-        if *current < text.len() {
-            return text[*current - 1];
+use crate::api::server_time;
+use crate::cache::fnv1a;
+use crate::manifest::{record_downloads, ManifestEntry};
+use crate::references::{article_references, citations, CitationFormat};
+use crate::site::host_of;
+use crate::wikidata::prefetch;
+
+use crate::{
+    plaintext_from_raw, raw_articles_from_links, render, render_with_text_length, FormatType,
+    OutputFormat, RawArticle, Source, Token, TITLES_PER_REQUEST,
+};
+
+// Next character, or '\0' once the text runs out
+pub fn advance(text: &[char], current: &mut usize) -> char {
+    match text.get(*current) {
+        Some(&character) => {
+            *current += 1;
+            character
         }
-        return '\0';
-    } else {
-        panic!("Infinite loop");
-        // exit(1);
+        None => '\0',
     }
 }
 
+pub fn char_at(text: &[char], current: usize) -> char {
+    text.get(current).copied().unwrap_or('\0')
+}
+
 pub fn add_token(tokens: &mut Vec<Token>, start: usize, current: usize, format: FormatType) {
     tokens.push(Token {
         start,
-        length: current - start - 1,
+        length: current.saturating_sub(start + 1),
         format,
     });
 }
@@ -42,11 +54,11 @@ pub fn add_new_line(tokens: &mut Vec<Token>, current: usize) {
     })
 }
 
-pub fn peek_ahead(text: &Vec<char>, current: usize) -> char {
+pub fn peek_ahead(text: &[char], current: usize) -> char {
     if current + 1 < text.len() {
         return text[current + 1];
     }
-    return '\0';
+    '\0'
 }
 
 pub fn add_space(tokens: &mut Vec<Token>, current: usize) {
@@ -57,61 +69,205 @@ pub fn add_space(tokens: &mut Vec<Token>, current: usize) {
     })
 }
 
-pub fn generate_plaintext(tokens: &Vec<Token>, characters: &Vec<char>) -> String {
-    let mut plaintext = String::new();
+#[derive(Clone, Copy, PartialEq)]
+pub enum Markup {
+    Plain,
+    Markdown,
+    Rst,
+}
+
+// Numbering and indentation of the list items seen so far
+#[derive(Default)]
+struct ListState {
+    // Markers of the last item, e.g. #*
+    markers: Vec<char>,
+    numbers: Vec<usize>,
+    // How far the text of the item at each depth is indented, nested items line up with it
+    widths: Vec<usize>,
+}
+
+impl ListState {
+    // What goes before the text of an item: the indentation and the bullet or number
+    fn item(&mut self, markers: &str, markup: Markup) -> String {
+        let markers: Vec<char> = markers.chars().collect();
+        let depth = markers.len();
+        let common = self
+            .markers
+            .iter()
+            .zip(&markers)
+            .take_while(|(previous, current)| previous == current)
+            .count();
+        self.numbers.truncate(common);
+        self.numbers.resize(depth, 0);
+        self.numbers[depth - 1] += 1;
+        self.widths.resize(depth - 1, 2);
+        self.markers = markers;
+
+        let bullet = match (self.markers[depth - 1], markup) {
+            ('#', _) => format!("{}. ", self.numbers[depth - 1]),
+            (';', _) => String::new(),
+            (':', Markup::Markdown) => ": ".to_string(),
+            (':', _) => "    ".to_string(),
+            _ => "- ".to_string(),
+        };
+        let indent = " ".repeat(self.widths.iter().sum());
+        self.widths.push(bullet.chars().count());
+        indent + &bullet
+    }
+
+    // RST wants blank lines around lists and wherever the nesting changes, except within a definition
+    fn needs_blank_line(&self, markers: &str) -> bool {
+        let markers: Vec<char> = markers.chars().collect();
+        let is_definition = |markers: &[char]| matches!(markers.last(), Some(';' | ':'));
+        let same_definition = self.markers.len() == markers.len()
+            && self.markers[..markers.len() - 1] == markers[..markers.len() - 1]
+            && is_definition(&self.markers)
+            && is_definition(&markers);
+        self.markers != markers && !same_definition
+    }
+}
+
+fn ensure_blank_line(text: &mut String) {
+    if !text.is_empty() && !text.ends_with("\n\n") {
+        text.push_str(if text.ends_with('\n') { "\n" } else { "\n\n" });
+    }
+}
+
+pub fn generate_text(tokens: &[Token], characters: &[char], markup: Markup) -> String {
+    let mut text = String::new();
     let get_text = |token: &Token| {
-        characters[token.start..token.start + token.length]
+        characters
+            .get(token.start..token.start + token.length)
+            .unwrap_or_default()
             .iter()
             .collect::<String>()
     };
+    // [[target|label]] shows the label, [[target]] and [[target|]] the target
+    let link_pattern = Regex::new(r"\[\[([^\[\]|]*)(?:\|([^\[\]]*))?\]\]").unwrap();
+    let wrapped = |text: &str, marker: &str| match text.trim() {
+        "" => String::new(),
+        text => format!("{}{}{}", marker, text, marker),
+    };
+    let mut list = ListState::default();
+    let mut at_line_start = true;
     // The parser is a hot pile of mess and needs to be rewritten asap
     for token in tokens {
+        let is_list_item = matches!(token.format, FormatType::ListItem);
+        if at_line_start && !is_list_item && !list.markers.is_empty() {
+            list = ListState::default();
+            if markup == Markup::Rst {
+                ensure_blank_line(&mut text);
+            }
+        }
+        at_line_start = matches!(token.format, FormatType::NewLine);
         match token.format {
-            FormatType::Title
-            | FormatType::Bold
+            FormatType::Title | FormatType::Subtitle | FormatType::Subsubtitle => {
+                let heading = get_text(token);
+                let level = match token.format {
+                    FormatType::Title => 0,
+                    FormatType::Subtitle => 1,
+                    _ => 2,
+                };
+                match markup {
+                    Markup::Plain => text.push_str(heading.trim()),
+                    Markup::Markdown => {
+                        text.push_str(&format!("{} {}", "#".repeat(level + 2), heading.trim()))
+                    }
+                    Markup::Rst => {
+                        let heading = heading.trim();
+                        let underline = ["=", "-", "~"][level].repeat(heading.chars().count());
+                        ensure_blank_line(&mut text);
+                        text.push_str(&format!("{}\n{}", heading, underline));
+                    }
+                }
+            }
+            FormatType::Bold | FormatType::BulletBold if markup != Markup::Plain => {
+                text.push_str(&wrapped(&get_text(token), "**"))
+            }
+            FormatType::BulletItalic if markup != Markup::Plain => {
+                text.push_str(&wrapped(&get_text(token), "*"))
+            }
+            // [[:Category:Rivers]] links to the category page rather than filing the article in it
+            FormatType::WikiLink => text.push_str(get_text(token).trim_start_matches(':')),
+            FormatType::Bold
             | FormatType::PlainWord
-            | FormatType::Subtitle
-            | FormatType::Subsubtitle
-            | FormatType::WikiLink
             | FormatType::BulletBold
-            | FormatType::BulletItalic => plaintext.push_str(&get_text(token)),
+            | FormatType::BulletItalic => text.push_str(&get_text(token)),
             FormatType::Italic | FormatType::InlineQuote => {
-                let text_with_artifact = &get_text(token).replace("[[", "");
-                let regex_pattern = Regex::new(r"\|.*?\]\]").unwrap();
-                let cleaned_text = regex_pattern
-                    .replace_all(text_with_artifact, "")
-                    .to_string();
-                plaintext.push_str(&cleaned_text.replace("]]", ""));
+                let token_text = get_text(token);
+                let cleaned_text = link_pattern
+                    .replace_all(&token_text, |caps: &Captures| {
+                        match caps
+                            .get(2)
+                            .and_then(|label| label.as_str().rsplit('|').next())
+                        {
+                            Some(label) if !label.is_empty() => label.to_string(),
+                            _ => caps[1].to_string(),
+                        }
+                    })
+                    // Whatever is left of links cut in half
+                    .replace("[[", "")
+                    .replace("]]", "");
+                match token.format {
+                    FormatType::Italic if markup != Markup::Plain => {
+                        text.push_str(&wrapped(&cleaned_text, "*"))
+                    }
+                    FormatType::InlineQuote => text.push_str(&format!("\"{}\"", cleaned_text)),
+                    _ => text.push_str(&cleaned_text),
+                }
             }
-            FormatType::Space => plaintext.push(' '),
-            FormatType::NewLine => plaintext.push('\n'),
+            FormatType::ListItem => {
+                let markers = get_text(token);
+                if markup == Markup::Rst && list.needs_blank_line(&markers) {
+                    ensure_blank_line(&mut text);
+                }
+                text.push_str(&list.item(&markers, markup));
+            }
+            FormatType::Space => text.push(' '),
+            FormatType::NewLine => text.push('\n'),
         }
     }
-    plaintext.trim().to_string()
+    text.trim().to_string()
 }
 
 pub fn output_to_stdout(plaintext_string: &str) {
     println!("{}", plaintext_string);
 }
 
-pub fn save_to_disk(
-    plaintext_string: &str,
-    article_title: &str,
-    hasher: &mut impl Hasher,
-    is_bulk: bool,
-) {
-    article_title.hash(hasher);
-    let hash = hasher.finish();
-    let hash = format!("{:x}.txt", hash);
-    let file_path;
-    if is_bulk {
-        file_path = format!("./wp_downloads/{}", hash);
+#[derive(Clone, Default)]
+pub struct DownloadOptions {
+    pub format: OutputFormat,
+    // Also save the raw wikitext so that the articles can be re-rendered later
+    pub keep_source: bool,
+    // Download the revisions that were current at this time instead of the latest ones
+    pub as_of: Option<String>,
+    // Output the infoboxes of the articles instead of their text
+    pub infobox: bool,
+    // Also export the references of the articles in this format
+    pub citations: Option<CitationFormat>,
+    pub source: Source,
+    // Add the Wikidata item of the articles and a few of its claims to the JSON output
+    pub wikidata: bool,
+    // Articles whose plain text is shorter than this are left out of bulk downloads
+    pub min_length: Option<usize>,
+}
+
+// Name of the files an article is saved to, minus the extension. It has to stay the same
+// between builds for `update` to find the files again
+pub fn file_stem(article_title: &str) -> String {
+    format!("{:016x}", fnv1a(article_title.as_bytes()))
+}
+
+pub fn save_to_disk(plaintext_string: &str, stem: &str, is_bulk: bool, extension: &str) {
+    let file_name = format!("{}.{}", stem, extension);
+    let file_path = if is_bulk {
+        format!("./wp_downloads/{}", file_name)
     } else {
-        file_path = format!("{}", hash);
-    }
+        file_name
+    };
     let path = Path::new(&file_path);
 
-    let mut file = match File::create(&path) {
+    let mut file = match File::create(path) {
         Err(why) => panic!("Error: Couldn't create {}: {}", path.display(), why),
         Ok(file) => file,
     };
@@ -126,6 +282,41 @@ pub fn save_to_disk(
     }
 }
 
+pub fn save_article(article: &RawArticle, options: &DownloadOptions, is_bulk: bool) {
+    save_rendered(article, &render(article, options), options, is_bulk);
+}
+
+// Saves an article rendered beforehand, along with whatever else the options ask for
+fn save_rendered(article: &RawArticle, rendered: &str, options: &DownloadOptions, is_bulk: bool) {
+    let stem = file_stem(&article.title);
+    save_to_disk(rendered, &stem, is_bulk, options.format.extension());
+    if options.keep_source {
+        save_to_disk(&source_json(article), &stem, is_bulk, SOURCE_EXTENSION);
+    }
+    if let Some(format) = options.citations {
+        save_to_disk(
+            &citations(&article_references(article), format),
+            &stem,
+            is_bulk,
+            format.extension(),
+        );
+    }
+}
+
+// What gets saved with --keep-source, everything needed to render the article again
+pub fn source_json(article: &RawArticle) -> String {
+    json!({
+        "title": article.title,
+        "host": article.host,
+        "revid": article.revid,
+        "timestamp": article.timestamp,
+        "raw_text": article.raw_text,
+    })
+    .to_string()
+}
+
+pub const SOURCE_EXTENSION: &str = "source.json";
+
 // Stolen straight from Rust by Examples :P
 pub fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
 where
@@ -135,17 +326,37 @@ where
     Ok(io::BufReader::new(file).lines())
 }
 
-pub fn download_from_file(link: &str) -> Option<bool> {
+// Stubs under the minimum length aren't worth keeping in a corpus. Formats without the text in
+// them (infoboxes, tables) still have to go by the plain text
+fn is_too_short(
+    article: &RawArticle,
+    text_length: Option<usize>,
+    min_length: Option<usize>,
+) -> bool {
+    min_length.is_some_and(|min| {
+        text_length.unwrap_or_else(|| plaintext_from_raw(article).chars().count()) < min
+    })
+}
+
+pub fn download_from_file(link: &str, options: &DownloadOptions) -> Option<bool> {
     use indicatif::ProgressBar;
     let mut list_of_links = vec![];
     if let Ok(lines) = read_lines(link) {
-        for line in lines.flatten() {
+        for line in lines.map_while(Result::ok) {
             list_of_links.push(line.trim().to_string());
         }
     }
     let mut handles = vec![];
     let total_count = &list_of_links.len();
 
+    // Anything edited after this might not make it into the download
+    let started_at = list_of_links
+        .first()
+        .and_then(|link| Url::parse(link).ok())
+        .and_then(|url| server_time(&host_of(&url)?).ok());
+    let downloaded = Arc::new(Mutex::new(vec![]));
+    let skipped = Arc::new(Mutex::new(0));
+
     let bar = Arc::new(Mutex::new(ProgressBar::new(
         (*total_count).try_into().unwrap(),
     )));
@@ -153,13 +364,49 @@ pub fn download_from_file(link: &str) -> Option<bool> {
     println!("🔍 Total links found: {}", total_count);
     println!("🗃️ Downloading articles in bulk in wp_downlods...\n");
 
-    for link in list_of_links {
+    // Articles are fetched in batches, one thread per batch
+    for batch in list_of_links.chunks(TITLES_PER_REQUEST) {
         let bar = Arc::clone(&bar);
+        let batch = batch.to_vec();
+        let options = options.clone();
+        let downloaded = Arc::clone(&downloaded);
+        let skipped = Arc::clone(&skipped);
         let handle = spawn(move || {
-            let (plaintext, url_title) = plaintext_from_link(&link);
-            let mut hasher = DefaultHasher::new();
-            save_to_disk(&plaintext, &url_title, &mut hasher, true);
-            bar.lock().unwrap().inc(1);
+            let articles = raw_articles_from_links(&batch, options.as_of.as_deref());
+            // One lookup for the whole batch rather than one per article
+            if options.wikidata && matches!(options.format, OutputFormat::Json) {
+                let fetched: Vec<&RawArticle> = articles.iter().flatten().collect();
+                if let Err(err) = prefetch(&fetched) {
+                    bar.lock()
+                        .unwrap()
+                        .println(format!("\x1b[31m⚠️ {}\x1b[0m", err));
+                }
+            }
+            for (link, article) in batch.iter().zip(articles) {
+                let article = article.map(|article| {
+                    let (rendered, text_length) = render_with_text_length(&article, &options);
+                    (article, rendered, text_length)
+                });
+                match article {
+                    Ok((article, _, text_length))
+                        if is_too_short(&article, text_length, options.min_length) =>
+                    {
+                        *skipped.lock().unwrap() += 1;
+                    }
+                    Ok((article, rendered, _)) => {
+                        save_rendered(&article, &rendered, &options, true);
+                        downloaded.lock().unwrap().push((
+                            file_stem(&article.title),
+                            ManifestEntry::from_article(&article),
+                        ));
+                    }
+                    Err(err) => bar
+                        .lock()
+                        .unwrap()
+                        .println(format!("\x1b[31m⚠️ {}: {}\x1b[0m", link, err)),
+                }
+                bar.lock().unwrap().inc(1);
+            }
         });
         handles.push(handle);
     }
@@ -172,6 +419,23 @@ pub fn download_from_file(link: &str) -> Option<bool> {
     }
     bar.lock().unwrap().finish_and_clear();
 
+    let downloaded = std::mem::take(&mut *downloaded.lock().unwrap());
+    if let Err(err) = record_downloads(
+        Path::new("wp_downloads"),
+        downloaded,
+        options.as_of.as_deref(),
+        started_at.as_deref(),
+    ) {
+        println!("\x1b[31m⚠️ Could not update the manifest: {}\x1b[0m", err);
+    }
+
+    let skipped = *skipped.lock().unwrap();
+    if skipped > 0 {
+        println!(
+            "✂️ Left out {} articles shorter than the minimum length",
+            skipped
+        );
+    }
     println!("\n✅ Download complete.");
     Some(true)
 }
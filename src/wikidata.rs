@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+
+use serde_json::{json, Value};
+
+use crate::api::{api_request, item_ids, normalize_title};
+use crate::site::profile;
+use crate::RawArticle;
+
+const INSTANCE_OF: &str = "P31";
+const COORDINATES: &str = "P625";
+// Dates worth filtering on, by property
+const DATES: [(&str, &str); 6] = [
+    ("P569", "birth"),
+    ("P570", "death"),
+    ("P571", "inception"),
+    ("P576", "dissolution"),
+    ("P577", "publication"),
+    ("P585", "point_in_time"),
+];
+// wbgetentities takes up to 50 ids at a time
+const ENTITIES_PER_REQUEST: usize = 50;
+
+#[derive(Clone)]
+pub struct Class {
+    pub id: String,
+    pub label: Option<String>,
+}
+
+// The Wikidata item of an article and the claims that tell what it is about
+#[derive(Clone)]
+pub struct Entity {
+    pub id: String,
+    pub instance_of: Vec<Class>,
+    // Latitude and longitude
+    pub coordinates: Option<(f64, f64)>,
+    pub dates: BTreeMap<String, String>,
+}
+
+// Entities already looked up, by host and title. None when the article has no item
+type EntityCache = HashMap<(String, String), Option<Entity>>;
+static ENTITIES: LazyLock<Mutex<EntityCache>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn main_value(claim: &Value) -> Option<&Value> {
+    if claim["rank"].as_str() == Some("deprecated") {
+        return None;
+    }
+    claim["mainsnak"]["datavalue"]["value"].as_object()?;
+    Some(&claim["mainsnak"]["datavalue"]["value"])
+}
+
+// Wikidata times look like +1690-08-24T00:00:00Z, shortened to what the precision says is known
+fn date(value: &Value) -> Option<String> {
+    let time = value["time"].as_str()?;
+    let (sign, time) = time.split_at(1);
+    let date = time.split('T').next()?;
+    let date = match value["precision"].as_i64()? {
+        11.. => date,
+        10 => date.get(..date.len() - 3)?,
+        _ => date.split('-').next()?,
+    };
+    Some(if sign == "-" {
+        format!("-{}", date)
+    } else {
+        date.to_string()
+    })
+}
+
+fn entity_from_claims(id: &str, claims: &Value) -> Entity {
+    let values = |property: &str| -> Vec<&Value> {
+        claims[property]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(main_value)
+            .collect()
+    };
+    Entity {
+        id: id.to_string(),
+        instance_of: values(INSTANCE_OF)
+            .into_iter()
+            .filter_map(|value| value["id"].as_str())
+            .map(|id| Class {
+                id: id.to_string(),
+                label: None,
+            })
+            .collect(),
+        coordinates: values(COORDINATES)
+            .first()
+            .and_then(|value| Some((value["latitude"].as_f64()?, value["longitude"].as_f64()?))),
+        dates: DATES
+            .iter()
+            .filter_map(|(property, name)| {
+                let date = values(property).first().and_then(|value| date(value))?;
+                Some((name.to_string(), date))
+            })
+            .collect(),
+    }
+}
+
+// Labels of the items in the language of the wiki, or in English
+fn labels(
+    wikidata_host: &str,
+    ids: &[String],
+    lang: &str,
+) -> Result<HashMap<String, String>, String> {
+    let mut labels = HashMap::new();
+    let languages = format!("{}|en", lang);
+    for chunk in ids.chunks(ENTITIES_PER_REQUEST) {
+        let response = api_request(
+            wikidata_host,
+            "wbgetentities",
+            &[
+                ("ids", &chunk.join("|")),
+                ("props", "labels"),
+                ("languages", &languages),
+            ],
+        )?;
+        for (id, entity) in response["entities"].as_object().into_iter().flatten() {
+            let label = [lang, "en"]
+                .iter()
+                .find_map(|lang| entity["labels"][lang]["value"].as_str());
+            if let Some(label) = label {
+                labels.insert(id.clone(), label.to_string());
+            }
+        }
+    }
+    Ok(labels)
+}
+
+// Entities of the items, as they are on the Wikibase the wiki is linked to
+pub fn entity_claims(host: &str, ids: &[String]) -> Result<HashMap<String, Entity>, String> {
+    let Some(wikidata_host) = profile(host).wikidata_host else {
+        return Ok(HashMap::new());
+    };
+    let mut entities = HashMap::new();
+    for chunk in ids.chunks(ENTITIES_PER_REQUEST) {
+        let response = api_request(
+            &wikidata_host,
+            "wbgetentities",
+            &[("ids", &chunk.join("|")), ("props", "claims")],
+        )?;
+        for (id, entity) in response["entities"].as_object().into_iter().flatten() {
+            entities.insert(id.clone(), entity_from_claims(id, &entity["claims"]));
+        }
+    }
+    Ok(entities)
+}
+
+// Entities of the items, with the classes they are instances of labelled
+pub fn entities(host: &str, ids: &[String]) -> Result<HashMap<String, Entity>, String> {
+    let mut entities = entity_claims(host, ids)?;
+    let Some(wikidata_host) = profile(host).wikidata_host else {
+        return Ok(entities);
+    };
+
+    let mut classes: Vec<String> = entities
+        .values()
+        .flat_map(|entity| entity.instance_of.iter().map(|class| class.id.clone()))
+        .collect();
+    classes.sort();
+    classes.dedup();
+    // Hosts like en.wikipedia.org start with the language of the wiki
+    let lang = host.split('.').next().unwrap_or("en");
+    let labels = labels(&wikidata_host, &classes, lang)?;
+    for entity in entities.values_mut() {
+        for class in &mut entity.instance_of {
+            class.label = labels.get(&class.id).cloned();
+        }
+    }
+    Ok(entities)
+}
+
+// Looks up the entities of a batch of articles of one wiki in a couple of requests, so that
+// rendering them one by one doesn't have to
+pub fn prefetch(articles: &[&RawArticle]) -> Result<(), String> {
+    let mut by_host: HashMap<&str, Vec<String>> = HashMap::new();
+    for article in articles {
+        let title = normalize_title(&article.title);
+        if !ENTITIES
+            .lock()
+            .unwrap()
+            .contains_key(&(article.host.clone(), title.clone()))
+        {
+            by_host.entry(&article.host).or_default().push(title);
+        }
+    }
+
+    for (host, titles) in by_host {
+        let items = item_ids(host, &titles)?;
+        let mut ids: Vec<String> = items.values().cloned().collect();
+        ids.sort();
+        ids.dedup();
+        let entities = entities(host, &ids)?;
+        let mut cached = ENTITIES.lock().unwrap();
+        for title in titles {
+            let entity = items.get(&title).and_then(|id| entities.get(id)).cloned();
+            cached.insert((host.to_string(), title), entity);
+        }
+    }
+    Ok(())
+}
+
+pub fn article_entity(article: &RawArticle) -> Result<Option<Entity>, String> {
+    let key = (article.host.clone(), normalize_title(&article.title));
+    if !ENTITIES.lock().unwrap().contains_key(&key) {
+        prefetch(&[article])?;
+    }
+    Ok(ENTITIES.lock().unwrap().get(&key).cloned().flatten())
+}
+
+pub fn entity_json(entity: &Entity) -> Value {
+    json!({
+        "item": entity.id,
+        "instance_of": entity
+            .instance_of
+            .iter()
+            .map(|class| json!({"id": class.id, "label": class.label}))
+            .collect::<Vec<_>>(),
+        "coordinates": entity
+            .coordinates
+            .map(|(latitude, longitude)| json!({"latitude": latitude, "longitude": longitude})),
+        "dates": entity.dates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_article_from_link;
+    use crate::test_support::wiki_link;
+
+    #[test]
+    fn shortens_dates_to_their_precision() {
+        let time =
+            |time: &str, precision: i64| date(&json!({"time": time, "precision": precision}));
+        assert_eq!(
+            time("+1690-08-24T00:00:00Z", 11).as_deref(),
+            Some("1690-08-24")
+        );
+        assert_eq!(
+            time("+1911-12-00T00:00:00Z", 10).as_deref(),
+            Some("1911-12")
+        );
+        assert_eq!(time("-0500-00-00T00:00:00Z", 9).as_deref(), Some("-0500"));
+    }
+
+    #[test]
+    fn looks_up_the_entity_of_an_article() {
+        let article = raw_article_from_link(&wiki_link("Kolkata"), None).unwrap();
+        let entity = article_entity(&article).unwrap().unwrap();
+        assert_eq!(
+            entity_json(&entity),
+            json!({
+                "item": "Q1348",
+                "instance_of": [
+                    {"id": "Q1637706", "label": "million city"},
+                    {"id": "Q515", "label": "city"},
+                ],
+                "coordinates": {"latitude": 22.5675, "longitude": 88.37},
+                "dates": {"inception": "1690-08-24"},
+            })
+        );
+    }
+}